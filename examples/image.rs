@@ -1,7 +1,7 @@
 use std::fs::File;
-use std::io::{ Read, Seek, SeekFrom, Write };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
 use core::cell::{RefCell};
-pub use super::fat_fs::storage_io::StorageIo;
+pub use fat_fs::storage_io::StorageIo;
 use core::ops::DerefMut;
 
 const IMAGE_SECTOR_SIZE: usize = 512;
@@ -12,23 +12,21 @@ pub struct Image {
 }
 
 impl StorageIo for Image {
+    type Error = io::Error;
+
     fn block_size(&self) -> u32 {
         IMAGE_SECTOR_SIZE as u32
     }
 
-    fn write(&self, block: u32, data: &[u8]) -> Result<(), bool> {
+    fn write(&self, block: u32, data: &[u8]) -> Result<(), io::Error> {
         assert!(data.len() == IMAGE_SECTOR_SIZE);
         println!("write sector: {}", block);
         let mut file = self.file.borrow_mut();
-        file.seek(SeekFrom::Start((block as u64) * (IMAGE_SECTOR_SIZE as u64))).unwrap();
-        if let Ok(_) = file.write(data) {
-            Ok(())
-        } else {
-            Err(false)
-        }
+        file.seek(SeekFrom::Start((block as u64) * (IMAGE_SECTOR_SIZE as u64)))?;
+        file.write_all(data)
     }
 
-    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), bool> {
+    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), io::Error> {
         assert!(data.len() == IMAGE_SECTOR_SIZE);
         
         let mut read_count = self.read_count.borrow_mut();
@@ -36,13 +34,8 @@ impl StorageIo for Image {
         *read_count_clean += 1;
 
         let mut file = self.file.borrow_mut();
-        file.seek(SeekFrom::Start((block as u64) * (IMAGE_SECTOR_SIZE as u64))).unwrap();
-
-        if let Ok(_) = file.read(data) {
-            Ok(())
-        } else {
-            Err(false)
-        }
+        file.seek(SeekFrom::Start((block as u64) * (IMAGE_SECTOR_SIZE as u64)))?;
+        file.read_exact(data)
     }
 
     fn block_count(&self) -> u32 {
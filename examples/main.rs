@@ -7,9 +7,8 @@ use fat_fs::Fs;
 use fat_fs::storage_io::StorageIo;
 //use fat_fs::stream::StreamPos;
 use fat_fs::dir::Dir;
+use fat_fs::date_time::SystemClock;
 //use fs::dir_reader::DirEntry;
-use std::fs::File;
-use std::io::prelude::*;
 
 fn print_str(s: &[u8]) {
     for c in s {
@@ -19,7 +18,8 @@ fn print_str(s: &[u8]) {
     println!();
 }
 
-fn print_tree<T: StorageIo>(dir: &Dir<T>, level: usize) {
+#[allow(dead_code)]
+fn print_tree<T: StorageIo, const N: usize>(dir: &Dir<T, N>, level: usize) {
     for dir_entry in dir.iter() {
 
         if dir_entry.name()[0] != b'.' {
@@ -72,7 +72,9 @@ fn main() {
     
     let img = image::new("F:/stay-on-main/fat16.img");
 
-    let fs = Fs::new(img).unwrap();
+    let mut fs: Fs<_, 8, SystemClock> = Fs::with_cache(img).unwrap();
+    fs.set_time_source(SystemClock);
+    fs.set_fat_pinning(true);
     let root = fs.root_dir();
     let queen_folder = root.dir_open(b"MyFolder/Queen").unwrap();
     //let mut file = root.file_open(b"MyFolder/Queen/Queen.txt").unwrap();
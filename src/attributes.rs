@@ -0,0 +1,75 @@
+use core::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+// Attribute byte of a directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes(u8);
+
+impl Attributes {
+    pub const READ_ONLY: Attributes = Attributes(0x01);
+    pub const HIDDEN: Attributes = Attributes(0x02);
+    pub const SYSTEM: Attributes = Attributes(0x04);
+    pub const VOLUME_ID: Attributes = Attributes(0x08);
+    pub const DIRECTORY: Attributes = Attributes(0x10);
+    pub const ARCHIVE: Attributes = Attributes(0x20);
+
+    // The attributes a user may change, the others describe the kind of entry.
+    pub const SETTABLE: Attributes = Attributes(0x01 | 0x02 | 0x04 | 0x20);
+
+    pub const fn empty() -> Attributes {
+        Attributes(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Attributes {
+        Attributes(bits & 0x3f)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn insert(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Attributes) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Attributes;
+
+    fn bitor(self, other: Attributes) -> Attributes {
+        Attributes(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Attributes {
+    fn bitor_assign(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for Attributes {
+    type Output = Attributes;
+
+    fn bitand(self, other: Attributes) -> Attributes {
+        Attributes(self.0 & other.0)
+    }
+}
+
+impl Not for Attributes {
+    type Output = Attributes;
+
+    fn not(self) -> Attributes {
+        Attributes(!self.0 & 0x3f)
+    }
+}
@@ -0,0 +1,86 @@
+use super::storage::StorageRead;
+use super::table::{FatTable, FatValue};
+use super::error::Error;
+
+// Cluster chain of a file compressed into runs of consecutive clusters, kept in
+// a caller supplied buffer as (chain index, first cluster) pairs so that the
+// cluster at any position is found with a binary search instead of a FAT walk.
+pub struct ClusterMap<'a> {
+    runs: &'a mut [[u32; 2]],
+    run_count: usize,
+    // number of clusters in the chain
+    length: u32,
+}
+
+// Follows the chain from `first` and calls `run` with the chain index and the
+// first cluster of every run. Returns the length of the chain.
+fn runs_walk<T: StorageRead, F: FnMut(u32, u32)>(table: &FatTable, io: &mut T, first: u32, mut run: F) -> Result<u32, Error<T::Error>> {
+    if first == 0 {
+        return Ok(0);
+    }
+
+    let mut cluster = first;
+    let mut index = 0;
+    run(0, first);
+
+    loop {
+        index += 1;
+
+        match table.get(io, cluster)? {
+            FatValue::Next(next) => {
+                if next != cluster + 1 {
+                    run(index, next);
+                }
+
+                cluster = next;
+            },
+            FatValue::Last => return Ok(index),
+            FatValue::Bad | FatValue::Free => return Err(Error::CorruptFat),
+        }
+    }
+}
+
+// Number of runs the chain starting at `first` is made of.
+pub fn runs_count<T: StorageRead>(table: &FatTable, io: &mut T, first: u32) -> Result<usize, Error<T::Error>> {
+    let mut count = 0;
+    runs_walk(table, io, first, |_, _| count += 1)?;
+    Ok(count)
+}
+
+impl<'a> ClusterMap<'a> {
+    pub fn new<T: StorageRead>(runs: &'a mut [[u32; 2]], table: &FatTable, io: &mut T, first: u32) -> Result<Self, Error<T::Error>> {
+        let mut run_count = 0;
+
+        let length = runs_walk(table, io, first, |index, cluster| {
+            if let Some(run) = runs.get_mut(run_count) {
+                *run = [index, cluster];
+            }
+
+            run_count += 1;
+        })?;
+
+        if run_count > runs.len() {
+            return Err(Error::BufferTooSmall);
+        }
+
+        Ok(ClusterMap { runs, run_count, length })
+    }
+
+    pub fn run_count(&self) -> usize {
+        self.run_count
+    }
+
+    // Returns the cluster at chain position `index`, or the last mapped one and
+    // its position if `index` lies past the end of the map.
+    pub fn nearest(&self, index: u32) -> Option<(u32, u32)> {
+        if self.length == 0 {
+            return None;
+        }
+
+        let index = core::cmp::min(index, self.length - 1);
+        let runs = &self.runs[..self.run_count];
+        let [run_index, run_cluster] = runs[runs.partition_point(|run| run[0] <= index) - 1];
+
+        Some((index, run_cluster + (index - run_index)))
+    }
+}
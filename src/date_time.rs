@@ -1,6 +1,6 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[derive(PartialEq)]
-struct FatDate {
+pub struct FatDate {
     day: u8,
     month: u8,
     year: u16,
@@ -15,21 +15,25 @@ impl FatDate {
         }
     }
 
+    pub fn serialize(&self) -> u16 {
+        u16::from(self.day) | (u16::from(self.month) << 5) | ((self.year - 1980) << 9)
+    }
+
     pub fn set_day(&mut self, day: u8) {
-        if (0 < day) && (day <= 31) {
+        if (1..=31).contains(&day) {
             self.day = day;
-        } 
+        }
     }
 
     pub fn set_month(&mut self, month: u8) {
-        if (0 < month) && (month <= 12) {
+        if (1..=12).contains(&month) {
             self.month = month;
         }
     }
 
     pub fn set_year(&mut self, year: u16) {
-        if (1980 <= year) && (year <= 2107) {
-            self.year = year - 1980;
+        if (1980..=2107).contains(&year) {
+            self.year = year;
         }
     }
 
@@ -46,9 +50,9 @@ impl FatDate {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[derive(PartialEq)]
-struct FatTime {
+pub struct FatTime {
     second: u8,
     minute: u8,
     hour: u8,
@@ -59,24 +63,29 @@ impl FatTime {
         FatTime {
             second: ((data & 0b11111) * 2) as u8,
             minute: ((data >> 5) & 0b111111) as u8,
-            hour: (data) >> 11) as u8,
+            hour: (data >> 11) as u8,
         }
     }
 
+    // The seconds are stored with a 2 second resolution.
+    pub fn serialize(&self) -> u16 {
+        u16::from(self.second / 2) | (u16::from(self.minute) << 5) | (u16::from(self.hour) << 11)
+    }
+
     pub fn set_second(&mut self, second: u8) {
-        if (0 <= second) && (second <= 59) {
+        if second <= 59 {
             self.second = second;
-        } 
+        }
     }
 
     pub fn set_minute(&mut self, minute: u8) {
-        if (0 <= minute) && (minute <= 59) {
+        if minute <= 59 {
             self.minute = minute;
         }
     }
 
     pub fn set_hour(&mut self, hour: u8) {
-        if (0 <= hour) && (hour <= 23) {
+        if hour <= 23 {
             self.hour = hour;
         }
     }
@@ -89,11 +98,146 @@ impl FatTime {
         self.minute
     }
 
-    pub fn hour(&self) -> u16 {
+    pub fn hour(&self) -> u8 {
         self.hour
     }
 }
 
+// Plain calendar date and time of a directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl DateTime {
+    // `tenths` is the creation time fine resolution field, counting 10 ms units
+    // from 0 to 199 on top of the 2 second resolution of `time`.
+    pub fn from_fat(date: FatDate, time: FatTime, tenths: u8) -> DateTime {
+        let tenths = core::cmp::min(tenths, 199);
+
+        DateTime {
+            year: date.year(),
+            month: date.month(),
+            day: date.day(),
+            hour: time.hour(),
+            minute: time.minute(),
+            second: time.second() + tenths / 100,
+            millisecond: u16::from(tenths % 100) * 10,
+        }
+    }
+
+    // True if every field but the year is in range, e.g. no 31st of April. The
+    // year is clamped by `to_fat` instead.
+    pub fn is_valid(&self) -> bool {
+        let days = match self.month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if self.year.is_multiple_of(4) && (!self.year.is_multiple_of(100) || self.year.is_multiple_of(400)) => 29,
+            2 => 28,
+            _ => return false,
+        };
+
+        (1..=days).contains(&self.day) && self.hour <= 23 && self.minute <= 59 &&
+            self.second <= 59 && self.millisecond <= 999
+    }
+
+    // Splits the time into the FAT date, time and 10 ms fields. Times outside
+    // of the years 1980 to 2107 FAT can store are clamped to the closest one,
+    // the other fields have to be valid.
+    pub fn to_fat(&self) -> (FatDate, FatTime, u8) {
+        if self.year < 1980 {
+            return (FatDate { day: 1, month: 1, year: 1980 }, FatTime { second: 0, minute: 0, hour: 0 }, 0);
+        }
+
+        if self.year > 2107 {
+            return (FatDate { day: 31, month: 12, year: 2107 }, FatTime { second: 58, minute: 59, hour: 23 }, 199);
+        }
+
+        let date = FatDate { day: self.day, month: self.month, year: self.year };
+        let time = FatTime { second: self.second & !1, minute: self.minute, hour: self.hour };
+        let tenths = (self.second & 1) * 100 + (core::cmp::min(self.millisecond, 999) / 10) as u8;
+
+        (date, time, tenths)
+    }
+
+    // Converts seconds since 1970-01-01 00:00:00 UTC.
+    pub fn from_unix_time(seconds: u64) -> DateTime {
+        let days = seconds / 86400;
+        let seconds = seconds % 86400;
+
+        // days to civil date, counting eras of 400 years starting at 0000-03-01
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let day_of_era = z - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+        DateTime {
+            year: core::cmp::min(year, u64::from(u16::MAX)) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+            millisecond: 0,
+        }
+    }
+}
+
+// Provides the current time used to stamp created and modified entries,
+// e.g. an RTC or the system clock.
+pub trait TimeSource {
+    fn now(&self) -> DateTime;
+}
+
+impl <S: TimeSource + ?Sized> TimeSource for &S {
+    fn now(&self) -> DateTime {
+        (**self).now()
+    }
+}
+
+// Time source of a volume without a clock, no entry gets a time stamp.
+pub enum NoTime {}
+
+impl TimeSource for NoTime {
+    fn now(&self) -> DateTime {
+        match *self {}
+    }
+}
+
+// Current time of the host in UTC, the system clock carries no time zone.
+#[cfg(feature = "std")]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl TimeSource for SystemClock {
+    fn now(&self) -> DateTime {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        DateTime::from_unix_time(seconds)
+    }
+}
+
+// Always returns the same time, for reproducible images.
+pub struct FixedTime(pub DateTime);
+
+impl TimeSource for FixedTime {
+    fn now(&self) -> DateTime {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -101,5 +245,49 @@ mod tests {
     fn fat_date_test() {
         let date = 15 | (4 << 5) | (17 << 9);
         assert_eq!(FatDate::deserialize(date), FatDate { day: 15, month: 4, year: 1997 });
+        assert_eq!(FatDate::deserialize(date).serialize(), date);
+    }
+
+    #[test]
+    fn fat_time_test() {
+        let time = 21 | (30 << 5) | (23 << 11);
+        assert_eq!(FatTime::deserialize(time), FatTime { second: 42, minute: 30, hour: 23 });
+        assert_eq!(FatTime::deserialize(time).serialize(), time);
+    }
+
+    #[test]
+    fn unix_time_test() {
+        let expected = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 5, millisecond: 0 };
+        assert_eq!(DateTime::from_unix_time(1_709_213_825), expected);
+        assert_eq!(DateTime::from_unix_time(0).year, 1970);
+
+        let (date, time, tenths) = expected.to_fat();
+        assert_eq!(DateTime::from_fat(date, time, tenths), expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn is_valid_test() {
+        let time = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 59, millisecond: 999 };
+        assert!(time.is_valid());
+        // 29th of February in leap years only
+        assert!(!DateTime { year: 1900, ..time }.is_valid());
+        assert!(DateTime { year: 2000, ..time }.is_valid());
+        assert!(!DateTime { year: 2023, ..time }.is_valid());
+        assert!(!DateTime { month: 4, day: 31, ..time }.is_valid());
+        assert!(!DateTime { month: 0, ..time }.is_valid());
+        assert!(!DateTime { month: 13, ..time }.is_valid());
+        assert!(!DateTime { day: 0, ..time }.is_valid());
+        assert!(!DateTime { hour: 24, ..time }.is_valid());
+        assert!(!DateTime { minute: 60, ..time }.is_valid());
+        assert!(!DateTime { second: 60, ..time }.is_valid());
+        assert!(!DateTime { millisecond: 1000, ..time }.is_valid());
+    }
+
+    #[test]
+    fn date_time_test() {
+        let date = FatDate::deserialize(1 | (12 << 5) | (44 << 9));
+        let time = FatTime::deserialize(5 | (59 << 5) | (12 << 11));
+        let expected = DateTime { year: 2024, month: 12, day: 1, hour: 12, minute: 59, second: 11, millisecond: 550 };
+        assert_eq!(DateTime::from_fat(date, time, 155), expected);
+    }
+}
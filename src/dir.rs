@@ -1,56 +1,594 @@
-use super::stream::Stream;
+use super::stream::{Stream, StreamKind};
 use super::storage_io::StorageIo;
 use super::Fs;
+use super::date_time::{NoTime, TimeSource};
 use super::dir_entry::DirEntry;
+use super::dir_entry;
+#[cfg(not(feature = "fs_read_only"))]
+use super::date_time::DateTime;
 use super::file::File;
 use super::path::Path;
-//const ATTR_READ_ONLY: u8 = 0x01;
-//const ATTR_HIDDEN: u8 = 0x02;
-//const ATTR_SYSTEM: u8 = 0x04;
-const ATTR_VOLUME_ID: u8 = 0x08;
-const ATTR_DIRECTORY: u8 = 0x10;
-//const ATTR_ARCHIVE: u8 = 0x20;
-//const ATTR_LONG_FILE_NAME: u8 = 0x0f;
-
-pub struct Dir <'a, T: StorageIo> {
-    fs: &'a Fs<T>,
+#[cfg(not(feature = "fs_read_only"))]
+use super::path::split_last;
+use super::error::Error;
+use super::attributes::Attributes;
+#[cfg(not(feature = "fs_read_only"))]
+use super::short_name;
+#[cfg(not(feature = "fs_read_only"))]
+use super::storage::{StorageRead, StorageWrite, WritePolicy, BLOCK_MAX_SIZE};
+#[cfg(not(feature = "fs_read_only"))]
+use super::table::FatValue;
+#[cfg(not(feature = "fs_read_only"))]
+use core::ops::DerefMut;
+const ATTR_VOLUME_ID: u8 = Attributes::VOLUME_ID.bits();
+#[cfg(not(feature = "fs_read_only"))]
+const ATTR_DIRECTORY: u8 = Attributes::DIRECTORY.bits();
+#[cfg(not(feature = "fs_read_only"))]
+const ATTR_ARCHIVE: u8 = Attributes::ARCHIVE.bits();
+
+pub struct Dir <'a, T: StorageIo, const N: usize = 1, C: TimeSource = NoTime> {
+    fs: &'a Fs<T, N, C>,
     cluster: u32,
 }
 
-impl <'a, T: StorageIo> Dir<'a, T> {
-    pub fn new(fs: &'a Fs<T>, cluster: u32) -> Self {
+impl <'a, T: StorageIo, const N: usize, C: TimeSource> Dir<'a, T, N, C> {
+    pub fn new(fs: &'a Fs<T, N, C>, cluster: u32) -> Self {
         Dir {
             fs, cluster
         }
     }
 
-    pub fn iter(&'a self) -> DirIterator<'a, T> {
+    pub fn iter(&'a self) -> DirIterator<'a, T, N, C> {
         DirIterator {
-            stream: Stream::new(self.fs, self.cluster, self.cluster == 0),
+            stream: Stream::new(self.fs, self.cluster, self.cluster == 0, StreamKind::Directory),
+            cluster: self.cluster,
+            index: 0,
         }
     }
 
-    pub fn file_open(&'a self, path: &[u8]) -> Result<File<'a, T>, bool> {
+    pub fn file_open(&self, path: &[u8]) -> Result<File<'a, T, N, C>, Error<T::Error>> {
         let entry = self.entry_find(path)?;
 
         if entry.is_file() {
-            return Ok(File::new(Stream::new(self.fs, entry.cluster, false), entry.size));
+            return Ok(File::new(Stream::new(self.fs, entry.cluster, false, StreamKind::Data), &entry));
+        }
+
+        Err(Error::NotAFile)
+    }
+
+    pub fn dir_open(&self, path: &[u8]) -> Result<Self, Error<T::Error>> {
+        let entry = self.entry_find(path)?;
+
+        if entry.is_dir() {
+            return Ok(Dir::new(self.fs, self.dir_cluster(&entry)));
+        }
+
+        Err(Error::NotADirectory)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn file_create(&self, path: &[u8]) -> Result<File<'a, T, N, C>, Error<T::Error>> {
+        let (parent, name) = split_last(path);
+        let dir = self.parent_open(parent)?;
+
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[11] = ATTR_ARCHIVE;
+
+        if let Some(now) = self.fs.now() {
+            dir_entry::times_set(&mut entry, Some(&now), Some(&now), Some(&now));
+        }
+
+        self.fs.dirty_mark()?;
+        dir.entry_create(name, entry)?;
+        let file = dir.file_open(name)?;
+
+        if self.fs.write_policy() == WritePolicy::WriteThrough {
+            self.fs.flush()?;
+        }
+
+        Ok(file)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn create_dir(&self, path: &[u8]) -> Result<(), Error<T::Error>> {
+        let (parent, name) = split_last(path);
+        let dir = self.parent_open(parent)?;
+
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+
+        if dir.iter().any(|e| e.compare_ignore_case(name)) {
+            return Err(Error::AlreadyExists);
+        }
+
+        self.fs.dirty_mark()?;
+
+        let cluster = {
+            let mut storage = self.fs.storage();
+            self.fs.table.alloc(storage.deref_mut(), None)?
+        };
+
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[11] = ATTR_DIRECTORY;
+        entry_cluster_set(&mut entry, cluster);
+
+        if let Some(now) = self.fs.now() {
+            dir_entry::times_set(&mut entry, Some(&now), Some(&now), Some(&now));
+        }
+
+        let result = dir.dir_init(cluster, &entry).and_then(|_| {
+            dir.entry_create(name, entry)
+        });
+
+        if let Err(e) = result {
+            // the cluster only leaks if this fails too, the first error is the one to report
+            let mut storage = self.fs.storage();
+            let _ = self.fs.table.free_chain(storage.deref_mut(), cluster);
+            return Err(e);
         }
 
-        Err(false)
+        self.fs.flush()
     }
 
-    pub fn dir_open(&'a self, path: &[u8]) -> Result<Self, bool> {
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn remove_dir(&self, path: &[u8]) -> Result<(), Error<T::Error>> {
         let entry = self.entry_find(path)?;
 
+        if !entry.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+
+        if entry.name() == b"." || entry.name() == b".." || entry.cluster == 0 {
+            return Err(Error::InvalidName);
+        }
+
+        let dir = Dir::new(self.fs, entry.cluster);
+
+        if dir.iter().any(|e| e.name() != b"." && e.name() != b"..") {
+            return Err(Error::DirectoryNotEmpty);
+        }
+
+        self.entry_remove(&entry)
+    }
+
+    // Moves the entry at `from` to `to`. Only the directory entries are rewritten,
+    // the cluster chain of the file or directory stays where it is.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn rename(&self, from: &[u8], to: &[u8]) -> Result<(), Error<T::Error>> {
+        let entry = self.entry_find(from)?;
+
+        if entry.name() == b"." || entry.name() == b".." {
+            return Err(Error::InvalidName);
+        }
+
+        let (parent, name) = split_last(to);
+        let dest = self.parent_open(parent)?;
+
         if entry.is_dir() {
-            return Ok(Dir::new(self.fs, entry.cluster));
+            // a directory can not become its own descendant
+            let mut cluster = dest.cluster;
+
+            while cluster != self.fs.root_dir_cluster() {
+                if cluster == entry.cluster {
+                    return Err(Error::InvalidMove);
+                }
+
+                let dir = Dir::new(self.fs, cluster);
+                cluster = dir.dir_cluster(&dir.entry_find(b"..")?);
+            }
+        }
+
+        self.fs.dirty_mark()?;
+
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.fs.storage().read(entry.entry_sector, entry.entry_offset, &mut raw)?;
+
+        let source = Dir::new(self.fs, entry.dir_cluster);
+        let first_slot = entry.slot_index + 1 - entry.slot_count;
+
+        let same_entry = dest.iter()
+            .find(|e| e.compare_ignore_case(name))
+            .map(|e| e.dir_cluster == entry.dir_cluster && e.slot_index == entry.slot_index);
+
+        match same_entry {
+            // only the case of the name changes, the old slots have to go first
+            // and come back if the new name does not fit
+            Some(true) => {
+                let mut marks = [DELETED_DIR_ENTRY; LFN_MAX_ENTRIES + 1];
+                let marks = &mut marks[..entry.slot_count];
+                source.slots_swap(first_slot, marks)?;
+
+                if let Err(e) = dest.entry_create(name, raw) {
+                    let _ = source.slots_swap(first_slot, marks);
+                    return Err(e);
+                }
+            },
+            Some(false) => return Err(Error::AlreadyExists),
+            None => {
+                dest.entry_create(name, raw)?;
+                source.slots_delete(first_slot, entry.slot_count)?;
+            },
+        }
+
+        if entry.is_dir() && dest.cluster != entry.dir_cluster {
+            let parent = if dest.cluster == self.fs.root_dir_cluster() { 0 } else { dest.cluster };
+            let mut dot_dot = [0u8; DIR_ENTRY_SIZE];
+            let sector = self.fs.cluster_to_sector(entry.cluster);
+            let mut storage = self.fs.storage();
+
+            storage.read(sector, DIR_ENTRY_SIZE, &mut dot_dot)?;
+            entry_cluster_set(&mut dot_dot, parent);
+            storage.write(sector, DIR_ENTRY_SIZE, &dot_dot)?;
+        }
+
+        self.fs.flush()
+    }
+
+    // Clears a freshly allocated directory cluster and writes the "." and ".."
+    // entries into it, they take the time stamps of `entry`, the entry of the
+    // new directory. This directory becomes the parent.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn dir_init(&self, cluster: u32, entry: &[u8; DIR_ENTRY_SIZE]) -> Result<(), Error<T::Error>> {
+        let mut storage = self.fs.storage();
+        let first_sector = self.fs.cluster_to_sector(cluster);
+        let zero = [0u8; 512];
+
+        for sector in first_sector..(first_sector + self.fs.sectors_in_cluster) {
+            for offset in (0..self.fs.sector_size as usize).step_by(zero.len()) {
+                storage.write(sector, offset, &zero)?;
+            }
+        }
+
+        // ".." of a directory in the root always points to cluster 0
+        let parent = if self.cluster == self.fs.root_dir_cluster() { 0 } else { self.cluster };
+
+        let mut entry = *entry;
+        entry[..11].copy_from_slice(b".          ");
+        entry_cluster_set(&mut entry, cluster);
+        storage.write(first_sector, 0, &entry)?;
+
+        entry[..11].copy_from_slice(b"..         ");
+        entry_cluster_set(&mut entry, parent);
+        storage.write(first_sector, DIR_ENTRY_SIZE, &entry)
+    }
+
+    // Cluster of the directory `entry` points to, ".." entries use 0 for the root.
+    fn dir_cluster(&self, entry: &DirEntry) -> u32 {
+        if entry.cluster == 0 {
+            self.fs.root_dir_cluster()
+        } else {
+            entry.cluster
+        }
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn parent_open(&self, parent: &[u8]) -> Result<Self, Error<T::Error>> {
+        if parent.is_empty() {
+            Ok(Dir::new(self.fs, self.cluster))
+        } else {
+            self.dir_open(parent)
+        }
+    }
+
+    // Writes the long name entries and the 8.3 `entry` for `name` into this directory.
+    // Everything in `entry` except the name field is kept as given.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entry_create(&self, name: &[u8], mut entry: [u8; DIR_ENTRY_SIZE]) -> Result<(), Error<T::Error>> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+
+        if self.iter().any(|e| e.compare_ignore_case(name)) {
+            return Err(Error::AlreadyExists);
+        }
+
+        let (basis, tail_needed) = short_name::basis(name);
+
+        let sfn = if !tail_needed && !self.sfn_exists(&basis) {
+            basis
+        } else {
+            let mut tail = 1;
+
+            loop {
+                let sfn = short_name::with_tail(&basis, name, tail);
+
+                if !self.sfn_exists(&sfn) {
+                    break sfn;
+                }
+
+                tail += 1;
+            }
+        };
+
+        entry[..11].copy_from_slice(&sfn);
+
+        let mut sfn_buf = [0u8; 12];
+        let sfn_len = short_name::display(&sfn, &mut sfn_buf);
+
+        // a name which is a valid upper case 8.3 name is stored without long name entries
+        let lfn_count = if sfn_buf[..sfn_len] == *name {
+            0
+        } else {
+            name.len().div_ceil(LFN_OFFSETS.len())
+        };
+        let sfn_checksum = checksum(&sfn);
+        let mut entries = [[0u8; DIR_ENTRY_SIZE]; LFN_MAX_ENTRIES + 1];
+
+        for (i, lfn) in entries.iter_mut().take(lfn_count).enumerate() {
+            let num = lfn_count - i;
+            lfn[0] = num as u8;
+
+            if i == 0 {
+                lfn[0] |= LAST_LONG_ENTRY;
+            }
+
+            lfn[11] = ATTR_LONG_NAME;
+            lfn[LDIR_CHKSUM] = sfn_checksum;
+
+            for (j, &offset) in LFN_OFFSETS.iter().enumerate() {
+                let pos = (num - 1) * LFN_OFFSETS.len() + j;
+
+                let (lo, hi) = match pos.cmp(&name.len()) {
+                    core::cmp::Ordering::Less => (name[pos], 0),
+                    core::cmp::Ordering::Equal => (0, 0),
+                    core::cmp::Ordering::Greater => (0xFF, 0xFF),
+                };
+
+                lfn[offset] = lo;
+                lfn[offset + 1] = hi;
+            }
+        }
+
+        entries[lfn_count] = entry;
+        self.entries_write(&entries[..=lfn_count])
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn sfn_exists(&self, sfn: &[u8; 11]) -> bool {
+        let mut buf = [0u8; 12];
+        let len = short_name::display(sfn, &mut buf);
+        self.iter().any(|e| e.sfn_buf[..e.sfn_len] == buf[..len])
+    }
+
+    // Finds `entries.len()` consecutive free slots and writes `entries` there.
+    // A cluster chain directory grows if there is not enough room.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entries_write(&self, entries: &[[u8; DIR_ENTRY_SIZE]]) -> Result<(), Error<T::Error>> {
+        let lenear = self.cluster == 0;
+        let mut stream = Stream::new(self.fs, self.cluster, lenear, StreamKind::Directory);
+        let mut data = [0u8; DIR_ENTRY_SIZE];
+        let mut index = 0;
+        let mut run_start = 0;
+        let mut run_len = 0;
+
+        let extend = loop {
+            match stream.read(&mut data) {
+                Ok(_) => (),
+                Err(Error::EndOfFile) if !lenear => break true,
+                Err(Error::EndOfFile) => return Err(Error::NoSpace),
+                Err(e) => return Err(e),
+            }
+
+            if data[0] == DELETED_DIR_ENTRY || data[0] == NO_MORE_DIR_ENTRY {
+                if run_len == 0 {
+                    run_start = index;
+                }
+
+                run_len += 1;
+
+                if run_len == entries.len() {
+                    break false;
+                }
+            } else {
+                run_len = 0;
+            }
+
+            index += 1;
+        };
+
+        if run_len == 0 {
+            run_start = index;
+        }
+
+        // the free slots at the end take what they can, the rest goes to new clusters
+        let (in_place, appended) = entries.split_at(if extend { run_len } else { entries.len() });
+
+        if extend {
+            // the new clusters come first, so running out of space leaves the
+            // directory as it was
+            let end = stream.cluster();
+            let mut last = end;
+
+            for chunk in appended.chunks(self.entries_per_cluster()) {
+                match self.cluster_append(last, chunk) {
+                    Ok(cluster) => last = cluster,
+                    Err(e) => {
+                        if last != end {
+                            let mut storage = self.fs.storage();
+                            let first = match self.fs.table.get(storage.deref_mut(), end)? {
+                                FatValue::Next(first) => first,
+                                _ => return Err(Error::CorruptFat),
+                            };
+                            self.fs.table.set(storage.deref_mut(), end, FatValue::Last)?;
+                            self.fs.table.free_chain(storage.deref_mut(), first)?;
+                        }
+
+                        return Err(e);
+                    },
+                }
+            }
+
+            self.fs.flush()?;
+        }
+
+        let mut stream = Stream::new(self.fs, self.cluster, lenear, StreamKind::Directory);
+
+        for _ in 0..run_start {
+            stream.read(&mut data)?;
+        }
+
+        for entry in in_place.iter() {
+            stream.write(entry)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entries_per_cluster(&self) -> usize {
+        (self.fs.sectors_in_cluster * self.fs.sector_size) as usize / DIR_ENTRY_SIZE
+    }
+
+    // Writes a free cluster holding `entries` followed by empty slots and links it
+    // after `last`. The cluster goes to the device before the FAT block linking
+    // it, so the directory never shows whatever the cluster held before.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn cluster_append(&self, last: u32, entries: &[[u8; DIR_ENTRY_SIZE]]) -> Result<u32, Error<T::Error>> {
+        let mut storage = self.fs.storage();
+        let cluster = self.fs.table.free_find(storage.deref_mut())?;
+        let first_sector = self.fs.cluster_to_sector(cluster);
+        let sector_size = self.fs.sector_size as usize;
+        let mut entries = entries.iter();
+        let mut buf = [0u8; BLOCK_MAX_SIZE];
+
+        for sector in first_sector..(first_sector + self.fs.sectors_in_cluster) {
+            let buf = &mut buf[..sector_size];
+            buf.fill(0);
+
+            for (slot, entry) in buf.chunks_mut(DIR_ENTRY_SIZE).zip(entries.by_ref()) {
+                slot.copy_from_slice(entry);
+            }
+
+            storage.write_blocks(sector, buf)?;
+        }
+
+        self.fs.table.alloc_at(storage.deref_mut(), cluster, Some(last))?;
+        Ok(cluster)
+    }
+
+    // Rewrites the time stamps of the entry at `path`, the ones given as None are kept.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_times(&self, path: &[u8], created: Option<DateTime>, modified: Option<DateTime>, accessed: Option<DateTime>) -> Result<(), Error<T::Error>> {
+        if [created, modified, accessed].iter().flatten().any(|time| !time.is_valid()) {
+            return Err(Error::InvalidDateTime);
+        }
+
+        self.entry_update(path, |entry| {
+            dir_entry::times_set(entry, created.as_ref(), modified.as_ref(), accessed.as_ref());
+        })
+    }
+
+    // Replaces the read only, hidden, system and archive attributes of the entry
+    // at `path`. Whether it is a directory can not be changed.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_attributes(&self, path: &[u8], attributes: Attributes) -> Result<(), Error<T::Error>> {
+        self.entry_update(path, |entry| {
+            let kept = Attributes::from_bits(entry[11]) & !Attributes::SETTABLE;
+            entry[11] = (kept | (attributes & Attributes::SETTABLE)).bits();
+        })
+    }
+
+    // Applies `update` to the 8.3 entry at `path` in place.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entry_update<F: FnOnce(&mut [u8; DIR_ENTRY_SIZE])>(&self, path: &[u8], update: F) -> Result<(), Error<T::Error>> {
+        let entry = self.entry_find(path)?;
+
+        // the root directory has no entry of its own
+        if entry.slot_count == 0 {
+            return Err(Error::InvalidName);
+        }
+
+        self.fs.dirty_mark()?;
+
+        {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            let mut storage = self.fs.storage();
+            storage.read(entry.entry_sector, entry.entry_offset, &mut raw)?;
+            update(&mut raw);
+            storage.write(entry.entry_sector, entry.entry_offset, &raw)?;
+        }
+
+        self.fs.flush()
+    }
+
+    // Replaces the label stored in the volume id entry of the root directory,
+    // the entry is created if missing and removed if `label` is None.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub(crate) fn label_set(&self, label: Option<&[u8; 11]>) -> Result<(), Error<T::Error>> {
+        let entry = self.iter().find(|e| e.is_volume_label());
+
+        match (entry, label) {
+            (Some(entry), Some(label)) => {
+                self.fs.storage().write(entry.entry_sector, entry.entry_offset, label)
+            },
+            (Some(entry), None) => self.slots_delete(entry.slot_index + 1 - entry.slot_count, entry.slot_count),
+            (None, Some(label)) => {
+                let mut entry = [0u8; DIR_ENTRY_SIZE];
+                entry[..11].copy_from_slice(label);
+                entry[11] = ATTR_VOLUME_ID;
+                self.entries_write(&[entry])
+            },
+            (None, None) => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn remove_file(&self, path: &[u8]) -> Result<(), Error<T::Error>> {
+        let entry = self.entry_find(path)?;
+
+        if !entry.is_file() {
+            return Err(Error::NotAFile);
+        }
+
+        self.entry_remove(&entry)
+    }
+
+    // Marks all slots of `entry` as deleted and frees its cluster chain.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entry_remove(&self, entry: &DirEntry) -> Result<(), Error<T::Error>> {
+        self.fs.dirty_mark()?;
+        Dir::new(self.fs, entry.dir_cluster).slots_delete(entry.slot_index + 1 - entry.slot_count, entry.slot_count)?;
+
+        if entry.cluster != 0 {
+            // the deleted slots reach the device before the chain is freed, a
+            // crash in between can only leak the clusters
+            let mut storage = self.fs.storage();
+            storage.flush()?;
+            self.fs.table.free_chain(storage.deref_mut(), entry.cluster)?;
+        }
+
+        self.fs.flush()
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn slots_delete(&self, first: usize, count: usize) -> Result<(), Error<T::Error>> {
+        let mut marks = [DELETED_DIR_ENTRY; LFN_MAX_ENTRIES + 1];
+        self.slots_swap(first, &mut marks[..count])
+    }
+
+    // Swaps the first byte of each slot starting at `first` with `marks`. Swapping
+    // in deleted marks and then the returned bytes restores the slots.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn slots_swap(&self, first: usize, marks: &mut [u8]) -> Result<(), Error<T::Error>> {
+        let mut stream = Stream::new(self.fs, self.cluster, self.cluster == 0, StreamKind::Directory);
+        let mut data = [0u8; DIR_ENTRY_SIZE];
+
+        for _ in 0..first {
+            stream.read(&mut data)?;
+        }
+
+        for mark in marks.iter_mut() {
+            stream.read(&mut data)?;
+            let (sector, offset) = stream.location();
+            self.fs.storage().write(sector, offset - DIR_ENTRY_SIZE, core::slice::from_ref(mark))?;
+            *mark = data[0];
         }
 
-        Err(false)
+        Ok(())
     }
 
-    pub fn entry_find(&self, path: &[u8]) -> Result<DirEntry, bool> {
+    pub fn entry_find(&self, path: &[u8]) -> Result<DirEntry, Error<T::Error>> {
         let mut current_dir = Dir::new(self.fs, self.cluster);
         let mut path = Path::new(path);
 
@@ -58,35 +596,39 @@ impl <'a, T: StorageIo> Dir<'a, T> {
             let mut found = false;
 
             for entry in current_dir.iter() {
-                if entry.compare(entry_name) {
+                if entry.compare_ignore_case(entry_name) {
                     if path.is_end() {
                         return Ok(entry);
                     }
 
-                    if entry.is_dir() {
-                        current_dir = Dir::new(self.fs, entry.cluster);
-                        found = true;
+                    if !entry.is_dir() {
+                        return Err(Error::NotADirectory);
                     }
 
+                    current_dir = Dir::new(self.fs, self.dir_cluster(&entry));
+                    found = true;
                     break;
                 }
             }
 
-            if found == false {
-                return Err(false);
+            if !found {
+                return Err(Error::NotFound);
             }
         }
         
-        Err(false)
+        Err(Error::NotFound)
     }
 }
 
-pub struct DirIterator <'a, T: StorageIo> {
-    stream: Stream<'a, T>,
+pub struct DirIterator <'a, T: StorageIo, const N: usize = 1, C: TimeSource = NoTime> {
+    stream: Stream<'a, T, N, C>,
+    cluster: u32,
+    // index of the next 32 byte slot to read
+    index: usize,
 }
 
 fn byte_to_lowercase(byte: u8) -> u8 {
-    if (byte >= b'A') && (byte <= b'Z') {
+    if byte.is_ascii_uppercase() {
         byte - b'A' + b'a'
     } else {
         byte
@@ -95,19 +637,22 @@ fn byte_to_lowercase(byte: u8) -> u8 {
 
 
 
-impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
+impl <'a, T: StorageIo, const N: usize, C: TimeSource> Iterator for DirIterator<'a, T, N, C> {
     type Item = DirEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut data = [0u8; 32];
+        let mut data = [0u8; DIR_ENTRY_SIZE];
         
         let mut lfn_buf = [0u8; 256];
         let mut lfn_checksum = 0u8;
         let mut lfn_num = 0u8;
         let mut lfn_len = 0;
+        let mut lfn_index = 0;
 
-        while let Ok(_) = self.stream.read(&mut data) {
+        while self.stream.read(&mut data).is_ok() {
             let attr = data[11];
+            let index = self.index;
+            self.index += 1;
 
             if data[0] == NO_MORE_DIR_ENTRY {
                 //println!("No more dir entry");
@@ -127,6 +672,7 @@ impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
     
                 if last_lfn {
                     lfn_checksum = data[LDIR_CHKSUM];
+                    lfn_index = index;
                 } else if lfn_checksum != data[LDIR_CHKSUM] || (data[0] + 1 ) != lfn_num {
                     //println!("Lfn corrupted part");
                     lfn_num = 0;
@@ -155,7 +701,9 @@ impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
                     }
                 }
             } else {
-                if (lfn_len != 0) && (lfn_num == 1) && (checksum(&data[0..11]) == lfn_checksum) {
+                let lfn_valid = (lfn_len != 0) && (lfn_num == 1) && (checksum(&data[0..11]) == lfn_checksum);
+
+                if lfn_valid {
                     //println!("Good lfn");
                     //print_str(&lfn_buf[..lfn_len]);
                 } else {
@@ -186,8 +734,8 @@ impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
                                 lfn_len += 1;
                             }
 
-                        if (data[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY)) == 0 && (data[8] != b' ') {
-                            // is file
+                        if (data[11] & ATTR_VOLUME_ID) == 0 && (data[8] != b' ') {
+                            // not a volume label
                             lfn_buf[lfn_len] = b'.';
                             lfn_len += 1;
             
@@ -223,8 +771,8 @@ impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
                     sfn_len += 1;
                 }
     
-                if (data[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY)) == 0 && (data[8] != b' ') {
-                    // is file
+                if (data[11] & ATTR_VOLUME_ID) == 0 && (data[8] != b' ') {
+                    // not a volume label
                     sfn_buf[sfn_len] = b'.';
                     sfn_len += 1;
     
@@ -241,7 +789,8 @@ impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
                 let cluster_hi = u32::from(super::u16_from_bytes(&data[20..]));
                 let cluster_lo = u32::from(super::u16_from_bytes(&data[26..]));
                 let cluster = (cluster_hi << 16) | cluster_lo;
-                let size = super::u32_from_bytes(&data[28..]);
+                let size = dir_entry::size_get(&data, self.stream.fs.fat_plus());
+                let (entry_sector, offset) = self.stream.location();
 
                 return Some(DirEntry {
                     cluster,
@@ -251,6 +800,17 @@ impl <'a, T: StorageIo> Iterator for DirIterator<'a, T> {
                     lfn_buf,
                     lfn_len,
                     attr, 
+                    entry_sector,
+                    entry_offset: offset - DIR_ENTRY_SIZE,
+                    dir_cluster: self.cluster,
+                    slot_index: index,
+                    slot_count: if lfn_valid { index - lfn_index + 1 } else { 1 },
+                    created_tenths: data[13],
+                    created_time: super::u16_from_bytes(&data[14..]),
+                    created_date: super::u16_from_bytes(&data[16..]),
+                    accessed_date: super::u16_from_bytes(&data[18..]),
+                    modified_time: super::u16_from_bytes(&data[22..]),
+                    modified_date: super::u16_from_bytes(&data[24..]),
                 });
             }
         }
@@ -270,9 +830,36 @@ const LDIR_CHKSUM: usize = 13;
 const DELETED_DIR_ENTRY: u8 = 0xE5;
 const NO_MORE_DIR_ENTRY: u8 = 0x00;
 
-//const DIR_ENTRY_SIZE: usize = 32;
+const DIR_ENTRY_SIZE: usize = 32;
 
 const LFN_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+#[cfg(not(feature = "fs_read_only"))]
+const LFN_MAX_ENTRIES: usize = 20;
+#[cfg(not(feature = "fs_read_only"))]
+const LFN_MAX_LEN: usize = 255;
+#[cfg(not(feature = "fs_read_only"))]
+const LFN_INVALID: &[u8] = b"\"*/:<>?\\|";
+
+#[cfg(not(feature = "fs_read_only"))]
+fn entry_cluster_set(entry: &mut [u8; DIR_ENTRY_SIZE], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+#[cfg(not(feature = "fs_read_only"))]
+fn is_valid_name(name: &[u8]) -> bool {
+    if name.is_empty() || name.len() > LFN_MAX_LEN || name == b"." || name == b".." {
+        return false;
+    }
+
+    if name.ends_with(b".") || name.ends_with(b" ") {
+        return false;
+    }
+
+    // long names are written and read one byte per UCS-2 unit, so only ASCII
+    // round trips
+    name.iter().all(|&b| (0x20..0x80).contains(&b) && !LFN_INVALID.contains(&b))
+}
 
 fn checksum(buf: &[u8]) -> u8 {
     let mut res = 0u8;
@@ -291,4 +878,288 @@ fn print_str(s: &[u8]) {
 
     println!();
 }
-*/
\ No newline at end of file
+*/
+
+#[cfg(test)]
+#[cfg(not(feature = "fs_read_only"))]
+mod tests {
+    use super::*;
+    use crate::test_image;
+    use crate::table::FatValue;
+
+    fn names<T: StorageIo>(dir: &Dir<'_, T>) -> std::vec::Vec<std::vec::Vec<u8>> {
+        dir.iter().map(|e| e.name().to_vec()).collect()
+    }
+
+    #[test]
+    fn file_create_test() {
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        root.file_create(b"A Long File Name.txt").unwrap();
+        root.file_create(b"SHORT.TXT").unwrap();
+        assert_eq!(names(&root), [&b"A Long File Name.txt"[..], b"SHORT.TXT"]);
+
+        // two long name slots before the 8.3 entry, each with its checksum
+        let entry = root.entry_find(b"A Long File Name.txt").unwrap();
+        assert_eq!(entry.slot_count, 3);
+        assert_eq!(&entry.sfn_buf[..entry.sfn_len], b"ALONGF~1.TXT");
+
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        let mut storage = fs.storage();
+        storage.read(entry.entry_sector, entry.entry_offset, &mut raw).unwrap();
+        let sfn_checksum = checksum(&raw[..11]);
+
+        for (slot, num) in [(1, 0x01), (0, 0x42)] {
+            storage.read(entry.entry_sector, slot * DIR_ENTRY_SIZE, &mut raw).unwrap();
+            assert_eq!((raw[0], raw[11], raw[LDIR_CHKSUM]), (num, ATTR_LONG_NAME, sfn_checksum));
+        }
+        drop(storage);
+
+        // an upper case 8.3 name needs no long name slot
+        assert_eq!(root.entry_find(b"SHORT.TXT").unwrap().slot_count, 1);
+
+        assert_eq!(root.file_create(b"short.txt").err(), Some(Error::AlreadyExists));
+
+        // lookups ignore the case of both the long and the 8.3 name
+        assert!(root.file_open(b"short.TXT").is_ok());
+        assert_eq!(root.entry_find(b"a long file name.TXT").unwrap().name(), b"A Long File Name.txt");
+        assert_eq!(root.entry_find(b"alongf~1.txt").unwrap().name(), b"A Long File Name.txt");
+        root.create_dir(b"Folder").unwrap();
+        root.file_create(b"FOLDER/Inner.txt").unwrap();
+        assert!(root.dir_open(b"folder").unwrap().file_open(b"INNER.TXT").is_ok());
+        root.remove_file(b"folder/inner.TXT").unwrap();
+        root.remove_dir(b"FOLDER").unwrap();
+        assert_eq!(root.file_create("café.txt".as_bytes()).err(), Some(Error::InvalidName));
+        assert_eq!(root.file_create(b"a:b").err(), Some(Error::InvalidName));
+    }
+
+    #[test]
+    fn dir_grow_test() {
+        // 1024 byte clusters hold 32 entries, "." and ".." take two of them
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        root.create_dir(b"many").unwrap();
+        let dir = root.dir_open(b"many").unwrap();
+
+        for i in 0..29u8 {
+            dir.file_create(&[b'F', b'0' + i / 10, b'0' + i % 10]).unwrap();
+        }
+
+        fs.flush().unwrap();
+        fs.storage().io().writes_take();
+
+        // one slot is left, the other two go to a new cluster
+        dir.file_create(b"A long name").unwrap();
+        let log = fs.storage().io().writes_take();
+
+        let second = match fs.table.get(&mut *fs.storage(), dir.cluster).unwrap() {
+            FatValue::Next(second) => second,
+            value => panic!("{:?}", value),
+        };
+
+        // the new cluster is on the device before the FAT links it
+        let new_sector = fs.cluster_to_sector(second);
+        let fat_write = log.iter().position(|&block| block < fs.cluster_to_sector(2)).unwrap();
+        assert_eq!(log[..fat_write], [new_sector, new_sector + 1]);
+
+        for i in 29..40u8 {
+            dir.file_create(&[b'F', b'0' + i / 10, b'0' + i % 10]).unwrap();
+        }
+
+        let found = names(&dir);
+        assert_eq!(found.len(), 43);
+        assert_eq!(found[31], b"A long name");
+        assert_eq!(found[42], b"F39");
+    }
+
+    #[test]
+    fn remove_file_test() {
+        let fs: Fs<_, 4> = Fs::with_cache(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        let free = fs.free_clusters(true).unwrap();
+
+        let mut file = root.file_create(b"to be removed.bin").unwrap();
+        file.write(&[1u8; 3000]).unwrap();
+        file.close().unwrap();
+        assert_eq!(fs.free_clusters(true).unwrap(), free - 3);
+
+        let entry = root.entry_find(b"to be removed.bin").unwrap();
+        assert_eq!(entry.slot_count, 3);
+        fs.storage().io().writes_take();
+        root.remove_file(b"to be removed.bin").unwrap();
+
+        // the deleted slots are on the device before the FAT frees the chain
+        let log = fs.storage().io().writes_take();
+        let fat_write = log.iter().position(|&block| block < fs.root_directory_first_sector).unwrap();
+        assert_eq!(log[..fat_write], [entry.entry_sector]);
+
+        // every slot is marked as deleted and the chain is free again
+        let mut storage = fs.storage();
+        for slot in 0..3 {
+            let mut first = 0u8;
+            storage.read(entry.entry_sector, slot * DIR_ENTRY_SIZE, core::slice::from_mut(&mut first)).unwrap();
+            assert_eq!(first, DELETED_DIR_ENTRY);
+        }
+        drop(storage);
+
+        assert_eq!(root.entry_find(b"to be removed.bin").err(), Some(Error::NotFound));
+        assert_eq!(fs.free_clusters(true).unwrap(), free);
+        assert_eq!(root.remove_file(b"to be removed.bin").err(), Some(Error::NotFound));
+    }
+
+    #[test]
+    fn create_dir_test() {
+        // FAT16 with a fixed root directory
+        let fs = Fs::new(test_image::format(32768, 4)).unwrap();
+        let root = fs.root_dir();
+        root.create_dir(b"outer").unwrap();
+        root.create_dir(b"outer/inner").unwrap();
+        assert_eq!(root.create_dir(b"OUTER").err(), Some(Error::AlreadyExists));
+
+        let outer = root.entry_find(b"outer").unwrap();
+        let inner = root.entry_find(b"outer/inner").unwrap();
+        assert!(outer.is_dir() && inner.is_dir());
+
+        // "." points to the directory itself, ".." to the parent or 0 for the root
+        let dots = |cluster| -> std::vec::Vec<(std::vec::Vec<u8>, u32, bool)> {
+            Dir::new(&fs, cluster).iter().take(2).map(|e| (e.name().to_vec(), e.cluster, e.is_dir())).collect()
+        };
+        assert_eq!(dots(outer.cluster), [(b".".to_vec(), outer.cluster, true), (b"..".to_vec(), 0, true)]);
+        assert_eq!(dots(inner.cluster), [(b".".to_vec(), inner.cluster, true), (b"..".to_vec(), outer.cluster, true)]);
+        assert_eq!(names(&Dir::new(&fs, inner.cluster)).len(), 2);
+
+        assert_eq!(root.remove_dir(b"outer").err(), Some(Error::DirectoryNotEmpty));
+        root.remove_dir(b"outer/inner").unwrap();
+        root.remove_dir(b"outer").unwrap();
+        assert_eq!(names(&root).len(), 0);
+    }
+
+    #[test]
+    fn rename_case_full_root_test() {
+        // FAT16 with a full fixed root directory of 512 entries
+        let fs = Fs::new(test_image::format(32768, 4)).unwrap();
+        let root = fs.root_dir();
+        let mut file = root.file_create(b"R0").unwrap();
+        file.write(&[5u8; 5000]).unwrap();
+        file.close().unwrap();
+
+        for i in 1..512u32 {
+            let name = [b'R', b'0' + (i / 100) as u8, b'0' + (i / 10 % 10) as u8, b'0' + (i % 10) as u8];
+            root.file_create(&name).unwrap();
+        }
+
+        let before = root.entry_find(b"R0").unwrap();
+        let free = fs.free_clusters(true).unwrap();
+
+        // the lower case name needs a long name slot there is no room for
+        assert_eq!(root.rename(b"R0", b"r0").err(), Some(Error::NoSpace));
+        let after = root.entry_find(b"R0").unwrap();
+        assert_eq!((after.cluster, after.size), (before.cluster, 5000));
+        assert_eq!(after.name(), b"R0");
+        assert_eq!(fs.free_clusters(true).unwrap(), free);
+
+        // with room again the same rename goes through
+        root.remove_file(b"R510").unwrap();
+        root.remove_file(b"R511").unwrap();
+        root.rename(b"R0", b"r0").unwrap();
+        let renamed = root.entry_find(b"r0").unwrap();
+        assert_eq!((renamed.name(), renamed.cluster), (&b"r0"[..], before.cluster));
+    }
+
+    #[test]
+    fn rename_move_test() {
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        root.create_dir(b"from").unwrap();
+        root.create_dir(b"from/sub").unwrap();
+        root.create_dir(b"to").unwrap();
+        root.file_create(b"from/sub/data.txt").unwrap().write(b"kept").unwrap();
+
+        let sub = root.entry_find(b"from/sub").unwrap();
+        let to = root.entry_find(b"to").unwrap();
+        root.rename(b"from/sub", b"to/Moved Dir").unwrap();
+
+        assert_eq!(root.entry_find(b"from/sub").err(), Some(Error::NotFound));
+        let moved = root.entry_find(b"to/Moved Dir").unwrap();
+        assert_eq!(moved.cluster, sub.cluster);
+        assert_eq!(names(&root.dir_open(b"from").unwrap()).len(), 2);
+
+        // ".." follows the move, back to 0 when moved into the root
+        let dot_dot = |dir: &[u8]| root.entry_find(dir).map(|e| Dir::new(&fs, e.cluster).entry_find(b"..").unwrap().cluster);
+        assert_eq!(dot_dot(b"to/Moved Dir"), Ok(to.cluster));
+        root.rename(b"to/Moved Dir", b"top").unwrap();
+        assert_eq!(dot_dot(b"top"), Ok(0));
+
+        let mut buf = [0u8; 8];
+        assert_eq!(root.file_open(b"top/data.txt").unwrap().read(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"kept");
+
+        assert_eq!(root.rename(b"to", b"to/inside").err(), Some(Error::InvalidMove));
+    }
+
+    #[test]
+    fn set_attributes_test() {
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        root.file_create(b"file.txt").unwrap();
+        root.create_dir(b"folder").unwrap();
+        let attributes = |path: &[u8]| root.entry_find(path).unwrap().attributes();
+
+        // the kind of an entry can not be changed
+        let kind = Attributes::DIRECTORY | Attributes::VOLUME_ID;
+        root.set_attributes(b"file.txt", Attributes::HIDDEN | kind).unwrap();
+        assert_eq!(attributes(b"file.txt"), Attributes::HIDDEN);
+        assert!(root.file_open(b"file.txt").is_ok());
+
+        root.set_attributes(b"folder", Attributes::READ_ONLY | Attributes::SYSTEM).unwrap();
+        assert_eq!(attributes(b"folder"), Attributes::DIRECTORY | Attributes::READ_ONLY | Attributes::SYSTEM);
+        root.set_attributes(b"folder", Attributes::ARCHIVE | Attributes::VOLUME_ID).unwrap();
+        assert_eq!(attributes(b"folder"), Attributes::DIRECTORY | Attributes::ARCHIVE);
+        assert!(root.dir_open(b"folder").is_ok());
+
+        assert_eq!(root.set_attributes(b"missing", Attributes::HIDDEN).err(), Some(Error::NotFound));
+    }
+
+    #[test]
+    fn set_times_test() {
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        root.create_dir(b"folder").unwrap();
+        root.file_create(b"folder/file.txt").unwrap();
+
+        let created = DateTime { year: 1999, month: 12, day: 31, hour: 23, minute: 59, second: 59, millisecond: 990 };
+        let modified = DateTime { year: 2107, month: 1, day: 2, hour: 3, minute: 4, second: 6, millisecond: 0 };
+        let accessed = DateTime { year: 2024, month: 2, day: 29, hour: 0, minute: 0, second: 0, millisecond: 0 };
+        root.set_times(b"folder/file.txt", Some(created), Some(modified), Some(accessed)).unwrap();
+
+        let entry = root.entry_find(b"folder/file.txt").unwrap();
+        assert_eq!(entry.created(), Some(created));
+        assert_eq!(entry.modified(), Some(modified));
+        assert_eq!(entry.accessed(), Some(accessed));
+
+        // only the given times change, the years are clamped to what FAT can store
+        let early = DateTime { year: 1970, ..created };
+        root.set_times(b"folder/file.txt", Some(early), None, None).unwrap();
+        let entry = root.entry_find(b"folder/file.txt").unwrap();
+        assert_eq!(entry.created(), Some(DateTime { year: 1980, month: 1, day: 1, hour: 0, minute: 0, second: 0, millisecond: 0 }));
+        assert_eq!(entry.modified(), Some(modified));
+        assert_eq!(entry.accessed(), Some(accessed));
+
+        // fields out of range are refused instead of spilling into their neighbours
+        let invalid = [
+            DateTime { month: 13, ..modified },
+            DateTime { day: 32, ..modified },
+            DateTime { month: 2, day: 30, ..modified },
+            DateTime { hour: 24, ..modified },
+            DateTime { minute: 60, ..modified },
+            DateTime { second: 60, ..modified },
+        ];
+
+        for time in invalid.iter() {
+            assert_eq!(root.set_times(b"folder/file.txt", None, Some(*time), None).err(), Some(Error::InvalidDateTime));
+            assert_eq!(root.set_times(b"folder", Some(*time), None, None).err(), Some(Error::InvalidDateTime));
+        }
+
+        assert_eq!(root.entry_find(b"folder/file.txt").unwrap().modified(), Some(modified));
+    }
+}
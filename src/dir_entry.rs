@@ -1,3 +1,5 @@
+use super::date_time::{DateTime, FatDate, FatTime};
+use super::attributes::Attributes;
 
 pub struct DirEntry {
     pub sfn_buf: [u8; 12],
@@ -6,16 +8,98 @@ pub struct DirEntry {
     pub lfn_len: usize,
     pub attr: u8,
     pub cluster: u32,
-    pub size: u32,
+    pub size: u64,
+    pub entry_sector: u32,
+    pub entry_offset: usize,
+    // cluster of the directory holding the entry
+    pub dir_cluster: u32,
+    // index of the 8.3 slot in the directory and the number of slots
+    // the entry occupies including the long name ones
+    pub slot_index: usize,
+    pub slot_count: usize,
+    // raw time stamps, a zero date means the field is not set
+    pub created_tenths: u8,
+    pub created_time: u16,
+    pub created_date: u16,
+    pub accessed_date: u16,
+    pub modified_time: u16,
+    pub modified_date: u16,
 }
 
-//const ATTR_READ_ONLY: u8 = 0x01;
-//const ATTR_HIDDEN: u8 = 0x02;
-//const ATTR_SYSTEM: u8 = 0x04;
-//const ATTR_VOLUME_ID: u8 = 0x08;
-const ATTR_DIRECTORY: u8 = 0x10;
-//const ATTR_ARCHIVE: u8 = 0x20;
-//const ATTR_LONG_FILE_NAME: u8 = 0x0f;
+const ATTR_VOLUME_ID: u8 = Attributes::VOLUME_ID.bits();
+const ATTR_DIRECTORY: u8 = Attributes::DIRECTORY.bits();
+
+// Size of the file from a raw entry. FAT+ keeps bits 32-34 of the size in bits
+// 0-2 of the reserved byte 12 and bits 35-37 in its bits 5-7, bits 3 and 4 are
+// the lowercase flags. The high bits are only used with `fat_plus` set.
+pub(crate) fn size_get(entry: &[u8], fat_plus: bool) -> u64 {
+    let size = u64::from(super::u32_from_bytes(&entry[28..]));
+
+    if fat_plus {
+        let high = u64::from(entry[12] & 0x07) | (u64::from(entry[12] >> 5) << 3);
+        size | (high << 32)
+    } else {
+        size
+    }
+}
+
+#[cfg(not(feature = "fs_read_only"))]
+pub(crate) fn size_set(entry: &mut [u8], size: u64, fat_plus: bool) {
+    entry[28..32].copy_from_slice(&(size as u32).to_le_bytes());
+
+    if fat_plus {
+        let high = (size >> 32) as u8;
+        entry[12] = (entry[12] & 0x18) | (high & 0x07) | ((high >> 3) << 5);
+    }
+}
+
+// Writes `time` into the creation fields of a raw 32 byte entry.
+#[cfg(not(feature = "fs_read_only"))]
+fn created_set(entry: &mut [u8], time: &DateTime) {
+    let (date, fat_time, tenths) = time.to_fat();
+    entry[13] = tenths;
+    entry[14..16].copy_from_slice(&fat_time.serialize().to_le_bytes());
+    entry[16..18].copy_from_slice(&date.serialize().to_le_bytes());
+}
+
+// Writes `time` into the modification fields of a raw entry.
+#[cfg(not(feature = "fs_read_only"))]
+fn modified_set(entry: &mut [u8], time: &DateTime) {
+    let (date, fat_time, _) = time.to_fat();
+    entry[22..24].copy_from_slice(&fat_time.serialize().to_le_bytes());
+    entry[24..26].copy_from_slice(&date.serialize().to_le_bytes());
+}
+
+// Only the date is stored for the last access.
+#[cfg(not(feature = "fs_read_only"))]
+fn accessed_set(entry: &mut [u8], time: &DateTime) {
+    let (date, _, _) = time.to_fat();
+    entry[18..20].copy_from_slice(&date.serialize().to_le_bytes());
+}
+
+// Updates the given time stamps of a raw entry, the others are kept.
+#[cfg(not(feature = "fs_read_only"))]
+pub(crate) fn times_set(entry: &mut [u8], created: Option<&DateTime>, modified: Option<&DateTime>, accessed: Option<&DateTime>) {
+    if let Some(time) = created {
+        created_set(entry, time);
+    }
+
+    if let Some(time) = modified {
+        modified_set(entry, time);
+    }
+
+    if let Some(time) = accessed {
+        accessed_set(entry, time);
+    }
+}
+
+fn date_time(date: u16, time: u16, tenths: u8) -> Option<DateTime> {
+    if date == 0 {
+        return None;
+    }
+
+    Some(DateTime::from_fat(FatDate::deserialize(date), FatTime::deserialize(time), tenths))
+}
 
 impl DirEntry{
     pub fn root(cluster: u32) -> Self {
@@ -27,10 +111,25 @@ impl DirEntry{
             attr: ATTR_DIRECTORY,
             cluster,
             size: 0,
+            entry_sector: 0,
+            entry_offset: 0,
+            dir_cluster: 0,
+            slot_index: 0,
+            slot_count: 0,
+            created_tenths: 0,
+            created_time: 0,
+            created_date: 0,
+            accessed_date: 0,
+            modified_time: 0,
+            modified_date: 0,
         }
     }
 
     pub fn compare(&self, name: &[u8]) -> bool {
+        if self.is_volume_label() {
+            return false;
+        }
+
         if name.len() == self.sfn_len {
             let mut equal = true;
 
@@ -72,6 +171,10 @@ impl DirEntry{
         true
         */
     }
+    pub fn compare_ignore_case(&self, name: &[u8]) -> bool {
+        !self.is_volume_label() && (self.sfn_buf[..self.sfn_len].eq_ignore_ascii_case(name) ||
+            self.lfn_buf[..self.lfn_len].eq_ignore_ascii_case(name))
+    }
     /*
     pub fn compare(&self, name: &[u8]) -> bool {
         let current_name = self.name();
@@ -123,12 +226,33 @@ impl DirEntry{
         (self.attr & ATTR_DIRECTORY) != 0
     }
 
+    pub fn attributes(&self) -> Attributes {
+        Attributes::from_bits(self.attr)
+    }
+
+    pub fn is_volume_label(&self) -> bool {
+        (self.attr & ATTR_VOLUME_ID) != 0
+    }
+
     pub fn is_file(&self) -> bool {
         (self.attr & ATTR_DIRECTORY) == 0
     }
 
-    pub fn size(&self) -> u32 {
+    pub fn size(&self) -> u64 {
         self.size
     }
+
+    pub fn created(&self) -> Option<DateTime> {
+        date_time(self.created_date, self.created_time, self.created_tenths)
+    }
+
+    pub fn modified(&self) -> Option<DateTime> {
+        date_time(self.modified_date, self.modified_time, 0)
+    }
+
+    // Only the date of the last access is recorded, the time is always midnight.
+    pub fn accessed(&self) -> Option<DateTime> {
+        date_time(self.accessed_date, 0, 0)
+    }
 }
 
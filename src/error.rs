@@ -0,0 +1,43 @@
+// Error returned by every fallible file system operation, `E` is the error
+// type of the underlying `StorageIo` device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    // The storage device failed to read or write a block.
+    Io(E),
+    // A block outside of the device was requested.
+    OutOfBounds,
+    // The boot sector does not describe a valid FAT volume.
+    InvalidBpb,
+    // The volume uses a FAT type disabled at compile time.
+    UnsupportedFatType,
+    // The FAT contains a free, bad or out of range cluster inside a chain.
+    CorruptFat,
+    // No entry with the given name exists.
+    NotFound,
+    // The entry exists but is not a directory.
+    NotADirectory,
+    // The entry exists but is not a file.
+    NotAFile,
+    // The end of a file or cluster chain has been reached.
+    EndOfFile,
+    // Seek to a negative position.
+    InvalidSeek,
+    // There are no free clusters left on the volume or no free entries
+    // left in the fixed FAT12/16 root directory.
+    NoSpace,
+    // An entry with the same name already exists.
+    AlreadyExists,
+    // The name is empty, too long or contains invalid characters.
+    InvalidName,
+    // The directory still contains entries.
+    DirectoryNotEmpty,
+    // A directory can not be moved into itself or one of its subdirectories.
+    InvalidMove,
+    // The write would grow the file past the 4 GiB - 1 byte FAT limit, or the
+    // 256 GiB - 1 byte FAT+ limit on volumes with FAT+ enabled.
+    FileTooLarge,
+    // A caller supplied buffer is too small for the result.
+    BufferTooSmall,
+    // A time stamp with a month, day, hour, minute or second out of range.
+    InvalidDateTime,
+}
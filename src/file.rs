@@ -1,49 +1,772 @@
 use super::stream::{Stream, StreamPos};
+#[cfg(not(feature = "fs_read_only"))]
+use super::stream::StreamKind;
 use super::storage_io::StorageIo;
+use super::date_time::{NoTime, TimeSource};
+use super::error::Error;
+use super::dir_entry::DirEntry;
+#[cfg(not(feature = "fs_read_only"))]
+use super::dir_entry;
+#[cfg(not(feature = "fs_read_only"))]
+use super::attributes::Attributes;
+#[cfg(not(feature = "fs_read_only"))]
+use super::storage::{StorageRead, StorageWrite, WritePolicy};
+#[cfg(not(feature = "fs_read_only"))]
+use super::table::FatValue;
+#[cfg(not(feature = "fs_read_only"))]
+use core::ops::DerefMut;
 
-pub struct File<'a, T: StorageIo> {
-    stream: Stream<'a, T>,
-    size: u32,
+// Largest size the 32 bit size field of a directory entry can hold.
+#[cfg(not(feature = "fs_read_only"))]
+const MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+// FAT+ adds 6 more bits of size.
+#[cfg(not(feature = "fs_read_only"))]
+const MAX_FILE_SIZE_FAT_PLUS: u64 = 0x3F_FFFF_FFFF;
+
+#[cfg(not(feature = "fs_read_only"))]
+const ATTR_ARCHIVE: u8 = Attributes::ARCHIVE.bits();
+
+pub struct File<'a, T: StorageIo, const N: usize = 1, C: TimeSource = NoTime> {
+    stream: Stream<'a, T, N, C>,
+    size: u64,
+    // location of the 32 byte directory entry of the file
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    entry_sector: u32,
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    entry_offset: usize,
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    dirty: bool,
 }
 
-impl <'a, T: StorageIo> File<'a, T> {
-    pub fn new(stream: Stream<'a, T>, size: u32) -> Self {
+impl <'a, T: StorageIo, const N: usize, C: TimeSource> File<'a, T, N, C> {
+    pub fn new(stream: Stream<'a, T, N, C>, entry: &DirEntry) -> Self {
         File {
             stream,
-            size,
+            size: entry.size,
+            entry_sector: entry.entry_sector,
+            entry_offset: entry.entry_offset,
+            dirty: false,
         }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, bool> {
-        let pos = self.stream.seek(StreamPos::Current(0))?;
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn max_size(&self) -> u64 {
+        if self.stream.fs.fat_plus() {
+            MAX_FILE_SIZE_FAT_PLUS
+        } else {
+            MAX_FILE_SIZE
+        }
+    }
+
+    // Keeps a map of the cluster chain in `runs` for fast seeking, one entry is
+    // needed per run of consecutive clusters, see `cluster_runs`. Returns the
+    // number of entries used.
+    pub fn cluster_map_set(&mut self, runs: &'a mut [[u32; 2]]) -> Result<usize, Error<T::Error>> {
+        self.stream.cluster_map_set(runs)
+    }
 
-        if pos == self.size {
-            return Err(false);
+    pub fn cluster_runs(&self) -> Result<usize, Error<T::Error>> {
+        self.stream.cluster_runs()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error<T::Error>> {
+        let pos = self.stream.seek(StreamPos::Current(0))?;
+        if pos >= self.size {
+            return Err(Error::EndOfFile);
         }
 
-        let bytes_to_read = core::cmp::min(buf.len(), (self.size - pos) as usize);
+        let bytes_to_read = core::cmp::min(buf.len() as u64, self.size - pos) as usize;
         let mut bytes_read = 0;
 
         while bytes_read < bytes_to_read {
-            if let Ok(read) = self.stream.read(&mut buf[bytes_read..bytes_to_read]) {
-                bytes_read += read;
-            } else if bytes_read == 0 {
-                return Err(false);
-            } else {
-                break;
+            match self.stream.read(&mut buf[bytes_read..bytes_to_read]) {
+                Ok(read) => bytes_read += read,
+                Err(e) if bytes_read == 0 => return Err(e),
+                Err(_) => break,
             }
         }
 
         Ok(bytes_read)
     }
 
-    pub fn seek(&mut self, pos: StreamPos) -> Result<u32, bool> {
-        self.stream.seek(pos)?;
-        Ok(42)
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error<T::Error>> {
+        // nothing to write, the volume stays clean
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.stream.fs.dirty_mark()?;
+        let pos = self.stream.seek(StreamPos::Current(0))?;
+        let len = core::cmp::min(buf.len() as u64, self.max_size().saturating_sub(pos)) as usize;
+
+        if len == 0 {
+            return Err(Error::FileTooLarge);
+        }
+
+        let mut bytes_written = 0;
+
+        while bytes_written < len {
+            match self.stream.write(&buf[bytes_written..len]) {
+                Ok(written) => bytes_written += written,
+                Err(e) if bytes_written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        let pos = self.stream.seek(StreamPos::Current(0))?;
+
+        if pos > self.size {
+            self.size = pos;
+        }
+
+        if bytes_written != 0 {
+            self.dirty = true;
+
+            if self.stream.fs.write_policy() == WritePolicy::WriteThrough {
+                self.flush()?;
+            }
+        }
+
+        Ok(bytes_written)
+    }
+
+    // Writes the size and the first cluster back to the directory entry.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn flush(&mut self) -> Result<(), Error<T::Error>> {
+        if self.dirty {
+            let cluster = self.stream.first_cluster();
+            let mut storage = self.stream.fs.storage();
+            let mut entry = [0u8; 32];
+            storage.read(self.entry_sector, self.entry_offset, &mut entry)?;
+
+            entry[11] |= ATTR_ARCHIVE;
+
+            if let Some(now) = self.stream.fs.now() {
+                dir_entry::times_set(&mut entry, None, Some(&now), Some(&now));
+            }
+
+            entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+            entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+            dir_entry::size_set(&mut entry, self.size, self.stream.fs.fat_plus());
+
+            storage.write(self.entry_sector, self.entry_offset, &entry)?;
+            self.dirty = false;
+        }
+
+        self.stream.fs.flush()
+    }
+
+    // Shrinks or grows the file to `new_len` bytes. When growing, the new part
+    // is filled with zeros if `zero_fill` is set, otherwise the clusters are only
+    // allocated and keep their previous content.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_len(&mut self, new_len: u64, zero_fill: bool) -> Result<(), Error<T::Error>> {
+        if new_len > self.max_size() {
+            return Err(Error::FileTooLarge);
+        }
+
+        let fs = self.stream.fs;
+        fs.dirty_mark()?;
+        let pos = self.stream.seek(StreamPos::Current(0))?;
+        let cluster_size = u64::from(fs.sectors_in_cluster * fs.sector_size);
+        let first_cluster = self.stream.first_cluster();
+
+        if new_len < self.size {
+            let keep = new_len.div_ceil(cluster_size) as u32;
+            // freed clusters may come back at other positions of the chain
+            self.stream.cluster_map_clear();
+            self.size = new_len;
+            self.dirty = true;
+
+            // the entry with the new size reaches the device before the clusters
+            // are freed, a crash in between can only leak them
+            if keep == 0 {
+                self.stream = Stream::new(fs, 0, false, StreamKind::Data);
+                self.flush()?;
+
+                if first_cluster != 0 {
+                    let mut storage = fs.storage();
+                    fs.table.free_chain(storage.deref_mut(), first_cluster)?;
+                }
+            } else {
+                self.flush()?;
+                let (last, _) = self.chain_walk(Some(keep - 1))?;
+                let mut storage = fs.storage();
+
+                if let FatValue::Next(next) = fs.table.get(storage.deref_mut(), last)? {
+                    fs.table.set(storage.deref_mut(), last, FatValue::Last)?;
+                    fs.table.free_chain(storage.deref_mut(), next)?;
+                }
+            }
+
+            self.stream.seek(StreamPos::Start(core::cmp::min(pos, new_len)))?;
+        } else if new_len > self.size {
+            if zero_fill {
+                let zero = [0u8; 512];
+                let mut remaining = new_len - self.size;
+                self.stream.seek(StreamPos::Start(self.size))?;
+
+                while remaining > 0 {
+                    let len = core::cmp::min(remaining, zero.len() as u64) as usize;
+                    remaining -= self.stream.write(&zero[..len])? as u64;
+                }
+            } else {
+                let needed = new_len.div_ceil(cluster_size) as u32;
+
+                let (mut last, mut count) = if first_cluster == 0 {
+                    let cluster = fs.table.alloc(fs.storage().deref_mut(), None)?;
+                    self.stream = Stream::new(fs, cluster, false, StreamKind::Data);
+                    (cluster, 1)
+                } else {
+                    self.chain_walk(None)?
+                };
+
+                let mut storage = fs.storage();
+
+                while count < needed {
+                    last = fs.table.alloc(storage.deref_mut(), Some(last))?;
+                    count += 1;
+                }
+            }
+
+            self.size = new_len;
+            self.dirty = true;
+            self.stream.seek(StreamPos::Start(pos))?;
+        }
+
+        if fs.write_policy() == WritePolicy::WriteThrough {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Cuts the file at the current position.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn truncate(&mut self) -> Result<(), Error<T::Error>> {
+        let pos = self.stream.seek(StreamPos::Current(0))?;
+        self.set_len(pos, false)
     }
 
-    pub fn close(self) {
+    // Follows the chain up to the cluster at position `index` or to its end,
+    // returns the cluster and the number of clusters passed including it.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn chain_walk(&self, index: Option<u32>) -> Result<(u32, u32), Error<T::Error>> {
+        let fs = self.stream.fs;
+        let mut storage = fs.storage();
+        let mut cluster = self.stream.first_cluster();
+        let mut count = 1;
+
+        while Some(count - 1) != index {
+            match fs.table.get(storage.deref_mut(), cluster)? {
+                FatValue::Next(next) => cluster = next,
+                FatValue::Last if index.is_none() => break,
+                FatValue::Last => return Err(Error::EndOfFile),
+                FatValue::Bad | FatValue::Free => return Err(Error::CorruptFat),
+            }
 
+            count += 1;
+        }
+
+        Ok((cluster, count))
+    }
+
+    // Moves to `pos` and returns the new position from the start of the file.
+    // Positions past the end are clamped to the size of the file.
+    pub fn seek(&mut self, pos: StreamPos) -> Result<u64, Error<T::Error>> {
+        let target = match pos {
+            StreamPos::Start(offset) => Some(offset),
+            StreamPos::Current(offset) => self.stream.seek(StreamPos::Current(0))?.checked_add_signed(offset),
+            StreamPos::End(offset) => self.size.checked_add_signed(offset),
+        };
+
+        let target = target.ok_or(Error::InvalidSeek)?;
+        self.stream.seek(StreamPos::Start(core::cmp::min(target, self.size)))
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn close(mut self) -> Result<(), Error<T::Error>> {
+        self.flush()
+    }
+
+    #[cfg(feature = "fs_read_only")]
+    pub fn close(self) -> Result<(), Error<T::Error>> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "fs_read_only"))]
+impl <'a, T: StorageIo, const N: usize, C: TimeSource> Drop for File<'a, T, N, C> {
+    fn drop(&mut self) {
+        let _ = self.flush();
     }
 }
 
+#[cfg(test)]
+#[cfg(not(feature = "fs_read_only"))]
+mod tests {
+    use super::*;
+    use crate::Fs;
+    use crate::test_image;
+    use crate::date_time::DateTime;
+
+    fn pattern(pos: u64) -> u8 {
+        (pos % 251) as u8
+    }
+
+    fn read_at(file: &mut File<'_, test_image::RamDisk>, pos: StreamPos) -> (u64, u8) {
+        let new_pos = file.seek(pos).unwrap();
+        let mut byte = 0u8;
+        file.read(core::slice::from_mut(&mut byte)).unwrap();
+        (new_pos, byte)
+    }
+
+    fn chain_length(fs: &Fs<test_image::RamDisk>, first: u32) -> u32 {
+        let mut storage = fs.storage();
+        let mut cluster = first;
+        let mut length = 1;
+
+        while let FatValue::Next(next) = fs.table.get(storage.deref_mut(), cluster).unwrap() {
+            cluster = next;
+            length += 1;
+        }
+
+        length
+    }
+
+    #[test]
+    fn write_remount_test() {
+        // FAT12 with 1024 byte clusters
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        fs.root_dir().file_create(b"grow.bin").unwrap().close().unwrap();
+
+        let entry = fs.root_dir().iter().find(|e| e.compare(b"grow.bin")).unwrap();
+        assert_eq!((entry.cluster, entry.size), (0, 0));
+
+        // the first write allocates the first cluster, the next ones cross sector
+        // and cluster boundaries and extend the chain
+        let data: [u8; 3100] = core::array::from_fn(|i| pattern(i as u64));
+        let mut file = fs.root_dir().file_open(b"grow.bin").unwrap();
+        assert_eq!(file.write(&data[..100]), Ok(100));
+        assert_ne!(file.stream.first_cluster(), 0);
+        assert_eq!(file.write(&data[100..1500]), Ok(1400));
+        assert_eq!(file.write(&data[1500..]), Ok(1600));
+        let first = file.stream.first_cluster();
+        file.close().unwrap();
+
+        let fs = Fs::new(fs.unmount().unwrap()).unwrap();
+        let entry = fs.root_dir().iter().find(|e| e.compare(b"grow.bin")).unwrap();
+        assert_eq!((entry.cluster, entry.size), (first, 3100));
+        assert_eq!(chain_length(&fs, first), 4);
+
+        let mut file = fs.root_dir().file_open(b"grow.bin").unwrap();
+        let mut buf = [0u8; 3200];
+        assert_eq!(file.read(&mut buf), Ok(3100));
+        assert_eq!(&buf[..3100], &data[..]);
+    }
+
+    #[test]
+    fn seek_cluster_boundaries_test() {
+        // FAT12, FAT16 and FAT32 with 1024 byte clusters
+        for sectors in [2048, 16384, 140_000] {
+            seek_cluster_boundaries(Fs::new(test_image::format(sectors, 2)).unwrap());
+        }
+    }
+
+    fn seek_cluster_boundaries(fs: Fs<test_image::RamDisk>) {
+        let mut file = fs.root_dir().file_create(b"seek.bin").unwrap();
+        let data: [u8; 5000] = core::array::from_fn(|i| pattern(i as u64));
+        assert_eq!(file.write(&data).unwrap(), data.len());
+
+        for pos in [0, 511, 512, 1023, 1024, 1025, 2047, 2048, 4096, 4999] {
+            assert_eq!(read_at(&mut file, StreamPos::Start(pos)), (pos, pattern(pos)));
+        }
+
+        // backwards over several clusters and forwards again
+        assert_eq!(read_at(&mut file, StreamPos::Start(3072)), (3072, pattern(3072)));
+        assert_eq!(read_at(&mut file, StreamPos::Current(-2050)), (1023, pattern(1023)));
+        assert_eq!(read_at(&mut file, StreamPos::Current(1024)), (2048, pattern(2048)));
+
+        assert_eq!(read_at(&mut file, StreamPos::End(-1)), (4999, pattern(4999)));
+        assert_eq!(read_at(&mut file, StreamPos::End(-3976)), (1024, pattern(1024)));
+    }
+
+    #[test]
+    fn seek_end_test() {
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let mut file = fs.root_dir().file_create(b"end.bin").unwrap();
+
+        // empty file without any cluster
+        assert_eq!(file.seek(StreamPos::End(0)).unwrap(), 0);
+        assert_eq!(file.seek(StreamPos::Start(10)).unwrap(), 0);
+
+        // a size of exactly two clusters ends on a cluster boundary
+        file.write(&[7u8; 2048]).unwrap();
+        assert_eq!(file.seek(StreamPos::End(0)).unwrap(), 2048);
+        assert_eq!(file.read(&mut [0u8; 4]), Err(Error::EndOfFile));
+
+        assert_eq!(file.seek(StreamPos::Start(5000)).unwrap(), 2048);
+        assert_eq!(file.seek(StreamPos::End(100)).unwrap(), 2048);
+        assert_eq!(file.seek(StreamPos::End(-2049)), Err(Error::InvalidSeek));
+        assert_eq!(file.seek(StreamPos::Current(-3000)), Err(Error::InvalidSeek));
+
+        // writing at the end after a seek appends
+        file.seek(StreamPos::Start(0)).unwrap();
+        file.seek(StreamPos::End(0)).unwrap();
+        file.write(&[9u8; 10]).unwrap();
+        assert_eq!(file.size(), 2058);
+        assert_eq!(read_at(&mut file, StreamPos::Start(2048)), (2048, 9));
+        assert_eq!(read_at(&mut file, StreamPos::Start(2047)), (2047, 7));
+    }
+
+    #[test]
+    fn cluster_map_test() {
+        // the map buffers have to outlive the file
+        let mut small = [[0u32; 2]; 2];
+        let mut runs = [[0u32; 2]; 3];
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let root = fs.root_dir();
+        let mut file = root.file_create(b"media.bin").unwrap();
+        let mut other = root.file_create(b"other.bin").unwrap();
+        let data: [u8; 12 * 1024] = core::array::from_fn(|i| pattern(i as u64));
+
+        // runs of 3, 2 and 4 clusters separated by clusters of the other file
+        for (start, end) in [(0, 3), (3, 5), (5, 9)] {
+            file.write(&data[start * 1024..end * 1024]).unwrap();
+            other.write(&[0u8; 1024]).unwrap();
+        }
+
+        assert_eq!(file.cluster_runs(), Ok(3));
+
+        assert_eq!(file.cluster_map_set(&mut small), Err(Error::BufferTooSmall));
+
+        assert_eq!(file.cluster_map_set(&mut runs), Ok(3));
+
+        for pos in [9215, 0, 3072, 3071, 5120, 1024, 8191, 2047, 5119, 6000] {
+            assert_eq!(read_at(&mut file, StreamPos::Start(pos)), (pos, pattern(pos)));
+        }
+
+        // the part the map does not cover is still found by walking the FAT
+        file.seek(StreamPos::End(0)).unwrap();
+        file.write(&data[9 * 1024..]).unwrap();
+
+        for pos in [12287, 100, 10240, 4000, 9216] {
+            assert_eq!(read_at(&mut file, StreamPos::Start(pos)), (pos, pattern(pos)));
+        }
+
+        // shrinking drops the map, the freed clusters may come back elsewhere
+        file.set_len(4096, false).unwrap();
+        other.write(&[0u8; 2048]).unwrap();
+        file.seek(StreamPos::End(0)).unwrap();
+        file.write(&data[4096..8192]).unwrap();
+
+        for pos in [8191, 4096, 0, 5000] {
+            assert_eq!(read_at(&mut file, StreamPos::Start(pos)), (pos, pattern(pos)));
+        }
+    }
+
+    #[test]
+    fn max_size_test() {
+        // FAT32 with 32 KiB clusters, large enough for a 4 GiB - 1 file
+        let fs = Fs::new(test_image::format(8_500_000, 64)).unwrap();
+        let root = fs.root_dir();
+        let mut file = root.file_create(b"huge.bin").unwrap();
+        file.set_len(0xFFFF_FFFF, false).unwrap();
+        assert_eq!(file.seek(StreamPos::End(0)).unwrap(), 0xFFFF_FFFF);
+
+        // past 2 GiB positions stay positive and the last byte is reachable
+        assert_eq!(file.seek(StreamPos::Start(0x8000_0000)).unwrap(), 0x8000_0000);
+        file.write(b"middle").unwrap();
+        assert_eq!(file.seek(StreamPos::End(-3)).unwrap(), 0xFFFF_FFFC);
+        assert_eq!(file.write(b"last"), Ok(3));
+        assert_eq!(file.write(b"x"), Err(Error::FileTooLarge));
+        assert_eq!(file.size(), 0xFFFF_FFFF);
+
+        assert_eq!(file.seek(StreamPos::Current(-0x7FFF_FFFF)).unwrap(), 0x8000_0000);
+        let mut buf = [0u8; 6];
+        assert_eq!(file.read(&mut buf), Ok(6));
+        assert_eq!(&buf, b"middle");
+        file.close().unwrap();
+
+        let mut file = root.file_open(b"huge.bin").unwrap();
+        assert_eq!(file.size(), 0xFFFF_FFFF);
+        assert_eq!(file.seek(StreamPos::Start(0xFFFF_FFFC)).unwrap(), 0xFFFF_FFFC);
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"las");
+        assert_eq!(file.read(&mut buf), Err(Error::EndOfFile));
+
+        file.set_len(0x8000_0002, false).unwrap();
+        assert_eq!(file.seek(StreamPos::End(-2)).unwrap(), 0x8000_0000);
+        assert_eq!(file.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"mi");
+    }
+
+    #[test]
+    #[cfg(feature = "fat_plus")]
+    fn fat_plus_test() {
+        // the high size bits share byte 12 with the lowercase flags
+        let mut entry = [0u8; 32];
+        entry[12] = 0x18;
+        dir_entry::size_set(&mut entry, 0x3F_0000_0001, true);
+        assert_eq!(entry[12], 0xFF);
+        assert_eq!(dir_entry::size_get(&entry, true), 0x3F_0000_0001);
+        // volumes without FAT+ ignore the bits
+        assert_eq!(dir_entry::size_get(&entry, false), 1);
+        dir_entry::size_set(&mut entry, 7, false);
+        assert_eq!(entry[12], 0xFF);
+        dir_entry::size_set(&mut entry, 5, true);
+        assert_eq!(entry[12], 0x18);
+
+        let mut fs = Fs::new(test_image::format(11_000_000, 64)).unwrap();
+
+        // every volume is plain FAT until it opts in
+        {
+            let mut file = fs.root_dir().file_create(b"plain.bin").unwrap();
+            assert_eq!(file.set_len(MAX_FILE_SIZE + 1, false), Err(Error::FileTooLarge));
+        }
+
+        fs.set_fat_plus(true);
+        let root = fs.root_dir();
+        let mut file = root.file_create(b"huge.bin").unwrap();
+        assert_eq!(file.set_len(MAX_FILE_SIZE_FAT_PLUS + 1, false), Err(Error::FileTooLarge));
+        file.set_len(0x1_4000_0000, false).unwrap();
+        assert_eq!(file.seek(StreamPos::End(-4)).unwrap(), 0x1_3FFF_FFFC);
+        file.write(b"tail").unwrap();
+        file.write(b"more").unwrap();
+        file.close().unwrap();
+
+        let mut file = root.file_open(b"huge.bin").unwrap();
+        assert_eq!(file.size(), 0x1_4000_0004);
+        assert_eq!(file.seek(StreamPos::Start(0x1_3FFF_FFFC)).unwrap(), 0x1_3FFF_FFFC);
+        let mut buf = [0u8; 8];
+        assert_eq!(file.read(&mut buf), Ok(8));
+        assert_eq!(&buf, b"tailmore");
+        drop(file);
+
+        let fs = Fs::new(fs.unmount().unwrap()).unwrap();
+        assert_eq!(fs.root_dir().entry_find(b"huge.bin").unwrap().size(), 0x4000_0004);
+    }
+
+    #[test]
+    fn multi_block_test() {
+        // FAT16 with 2 KiB clusters
+        let fs = Fs::new(test_image::format(32768, 4)).unwrap();
+        let data: std::vec::Vec<u8> = (0..0x10000u32).map(|i| (i * 7 / 3) as u8).collect();
+        let mut file = fs.root_dir().file_create(b"bulk.bin").unwrap();
+        file.write(&data[..100]).unwrap();
+        assert_eq!(file.write(&data[100..]), Ok(0x10000 - 100));
+        file.close().unwrap();
+
+        let disk = fs.unmount().unwrap();
+        disk.requests_take();
+        let fs = Fs::new(disk).unwrap();
+        let mut file = fs.root_dir().file_open(b"bulk.bin").unwrap();
+        let mut buf = std::vec![0u8; 0x10000];
+        assert_eq!(file.read(&mut buf), Ok(0x10000));
+        assert_eq!(buf, data);
+        drop(file);
+
+        // the 128 sectors are read with one request, the rest is mount and FAT
+        let disk = fs.unmount().unwrap();
+        assert!(disk.requests_take() < 10);
+        let fs = Fs::new(disk).unwrap();
+
+        // unaligned reads and writes across the bulk ranges stay consistent
+        let mut file = fs.root_dir().file_open(b"bulk.bin").unwrap();
+        file.seek(StreamPos::Start(1000)).unwrap();
+        file.write(&[0xAA; 5000]).unwrap();
+        file.seek(StreamPos::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buf), Ok(0x10000));
+        assert_eq!(&buf[..1000], &data[..1000]);
+        assert!(buf[1000..6000].iter().all(|&b| b == 0xAA));
+        assert_eq!(&buf[6000..], &data[6000..]);
+    }
+
+    #[test]
+    fn write_policy_test() {
+        let fs: Fs<_, 4> = Fs::with_cache(test_image::format(32768, 4)).unwrap();
+        let mut file = fs.root_dir().file_create(b"log.txt").unwrap();
+        let writes = || fs.storage().io().writes_take();
+
+        // write-through: the data, then the new cluster in both FATs, then the entry
+        fs.set_write_policy(WritePolicy::WriteThrough).unwrap();
+        writes();
+        file.write(b"first").unwrap();
+        let data_sector = fs.cluster_to_sector(file.stream.first_cluster());
+        let log = writes();
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[0], data_sector);
+        assert!(log[1] < data_sector && log[2] < data_sector);
+        assert_eq!(log[3], file.entry_sector);
+
+        // write-back: nothing until the flush, which keeps the same order
+        fs.set_write_policy(WritePolicy::WriteBack).unwrap();
+        file.write(b"second").unwrap();
+        assert!(writes().is_empty());
+        file.flush().unwrap();
+        assert_eq!(writes(), [data_sector, file.entry_sector]);
+
+        // write-through leaves no dirty block behind when the metadata alone changes
+        fs.set_write_policy(WritePolicy::WriteThrough).unwrap();
+        let settled = || {
+            let written = !writes().is_empty();
+            fs.flush().unwrap();
+            written && writes().is_empty()
+        };
+
+        let mut other = fs.root_dir().file_create(b"other.txt").unwrap();
+        assert!(settled());
+        other.set_len(10000, false).unwrap();
+        assert!(settled());
+        other.set_len(3000, false).unwrap();
+        assert!(settled());
+        other.seek(StreamPos::Start(1000)).unwrap();
+        other.truncate().unwrap();
+        assert!(settled());
+        assert_eq!(other.size(), 1000);
+    }
+
+    #[test]
+    fn single_entry_write_order_test() {
+        for policy in [WritePolicy::WriteThrough, WritePolicy::WriteBack].iter().copied() {
+            // a single cache entry, the FAT block is evicted by the data written next
+            let fs = Fs::new(test_image::format(32768, 4)).unwrap();
+            fs.set_write_policy(policy).unwrap();
+            let mut file = fs.root_dir().file_create(b"log.txt").unwrap();
+            fs.flush().unwrap();
+            let writes = || fs.storage().io().writes_take();
+            let kind = |block: u32| if block >= fs.data_area_first_sector {
+                0
+            } else if block < fs.root_directory_first_sector {
+                1
+            } else {
+                2
+            };
+
+            // data, then FAT, then the entry, the FAT only changes with new clusters
+            let check = |file: &mut File<'_, _>, data: &[u8], grows: bool| {
+                writes();
+                file.write(data).unwrap();
+                file.flush().unwrap();
+                let log: std::vec::Vec<u32> = writes().into_iter().map(kind).collect();
+                assert!(log.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", log);
+                assert_eq!(log.contains(&1), grows);
+                assert_eq!(log.last(), Some(&2));
+            };
+
+            // the first cluster, the rest of it, a new one and several at once
+            check(&mut file, b"first", true);
+            check(&mut file, &[0x11; 2043], false);
+            check(&mut file, &[0x22; 512], true);
+            check(&mut file, &[0x33; 8 * 512], true);
+            assert_eq!(file.size(), 6656);
+            assert_eq!(chain_length(&fs, file.stream.first_cluster()), 4);
+        }
+    }
+
+    #[test]
+    fn empty_write_test() {
+        let disk = test_image::format(2048, 2);
+        Fs::new(&disk).unwrap().root_dir().file_create(b"a.txt").unwrap();
+        disk.writes_take();
+
+        let fs = Fs::new(&disk).unwrap();
+        let mut file = fs.root_dir().file_open(b"a.txt").unwrap();
+        assert_eq!(file.write(&[]), Ok(0));
+        drop(file);
+        fs.unmount().unwrap();
+        assert!(disk.writes_take().is_empty());
+    }
+
+    #[test]
+    fn set_len_write_order_test() {
+        let fs: Fs<_, 4> = Fs::with_cache(test_image::format(32768, 4)).unwrap();
+        fs.set_write_policy(WritePolicy::WriteThrough).unwrap();
+        let mut file = fs.root_dir().file_create(b"log.txt").unwrap();
+        let writes = || fs.storage().io().writes_take();
+
+        // the streams replacing an emptied chain still write file data first
+        let check = |file: &mut File<'_, _, 4>| {
+            writes();
+            file.write(b"data").unwrap();
+            let data_sector = fs.cluster_to_sector(file.stream.first_cluster());
+            let log = writes();
+            assert_eq!(log.first(), Some(&data_sector));
+            assert!(log[1..log.len() - 1].iter().all(|&block| block < data_sector));
+            assert_eq!(log.last(), Some(&file.entry_sector));
+        };
+
+        check(&mut file);
+        file.set_len(0, false).unwrap();
+        check(&mut file);
+        file.seek(StreamPos::Start(0)).unwrap();
+        file.truncate().unwrap();
+        check(&mut file);
+
+        file.set_len(0, false).unwrap();
+        file.set_len(100, false).unwrap();
+        file.seek(StreamPos::Start(0)).unwrap();
+        check(&mut file);
+
+        // shrinking writes the smaller size before the FAT frees the clusters
+        file.set_len(3 * 2048, false).unwrap();
+
+        for len in [100, 0] {
+            writes();
+            file.set_len(len, false).unwrap();
+            let log = writes();
+            let fat_write = log.iter().position(|&block| block < fs.root_directory_first_sector).unwrap();
+            assert_eq!(log[..fat_write], [file.entry_sector]);
+        }
+    }
+
+    struct TestClock(core::cell::Cell<DateTime>);
+
+    impl TimeSource for TestClock {
+        fn now(&self) -> DateTime {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn time_stamp_test() {
+        let created = DateTime { year: 2024, month: 3, day: 9, hour: 8, minute: 15, second: 31, millisecond: 250 };
+        let written = DateTime { year: 2025, month: 11, day: 30, hour: 23, minute: 59, second: 58, millisecond: 0 };
+        let midnight = |time: DateTime| DateTime { hour: 0, minute: 0, second: 0, millisecond: 0, ..time };
+        // the clock is borrowed, it does not have to outlive the test
+        let clock = TestClock(core::cell::Cell::new(created));
+
+        let mut fs: Fs<_, 1, &TestClock> = Fs::with_cache(test_image::format(2048, 2)).unwrap();
+        fs.set_time_source(&clock);
+        let root = fs.root_dir();
+        root.file_create(b"stamped.txt").unwrap();
+
+        let entry = root.entry_find(b"stamped.txt").unwrap();
+        assert_eq!(entry.created(), Some(created));
+        // the modification time only has a 2 second resolution
+        assert_eq!(entry.modified(), Some(DateTime { second: 30, millisecond: 0, ..created }));
+        assert_eq!(entry.accessed(), Some(midnight(created)));
+
+        clock.0.set(written);
+        let mut file = root.file_open(b"stamped.txt").unwrap();
+        file.write(b"data").unwrap();
+        drop(file);
+
+        let entry = root.entry_find(b"stamped.txt").unwrap();
+        assert_eq!(entry.created(), Some(created));
+        assert_eq!(entry.modified(), Some(written));
+        assert_eq!(entry.accessed(), Some(midnight(written)));
+
+        // without a clock nothing is stamped
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        fs.root_dir().file_create(b"plain.txt").unwrap();
+        let entry = fs.root_dir().entry_find(b"plain.txt").unwrap();
+        assert_eq!(entry.created(), None);
+        assert_eq!(entry.modified(), None);
+    }
+}
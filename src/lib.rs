@@ -8,16 +8,33 @@ pub mod dir;
 pub mod dir_entry;
 pub mod file;
 pub mod path;
+#[cfg(not(feature = "fs_read_only"))]
+mod short_name;
+pub mod error;
+pub mod volume;
+pub mod date_time;
+pub mod attributes;
+mod cluster_map;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+#[cfg(test)]
+mod test_image;
 
-use path::Path;
-use file::File;
 use dir::Dir;
-use dir_entry::DirEntry;
-use storage::{Storage, StorageRead, StorageWrite};
+use error::Error;
+use storage::{Storage, StorageRead};
+#[cfg(not(feature = "fs_read_only"))]
+use storage::{StorageWrite, WritePolicy};
 use storage_io::StorageIo;
 use table::{FatTable, FatType};
-use core::cell::RefCell;
-use stream::Stream;
+use volume::{Stats, VolumeInfo};
+use date_time::{NoTime, TimeSource};
+#[cfg(not(feature = "fs_read_only"))]
+use date_time::DateTime;
+use core::cell::{RefCell, RefMut};
+#[cfg(not(feature = "fs_read_only"))]
+use core::cell::Cell;
+use core::ops::DerefMut;
 
 fn u32_from_bytes(bytes: &[u8]) -> u32 {
     u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) |
@@ -28,19 +45,48 @@ fn u16_from_bytes(bytes: &[u8]) -> u16 {
     u16::from(bytes[0]) | (u16::from(bytes[1]) << 8)
 }
 
-pub struct Fs <T: StorageIo> {
-    pub storage: RefCell<Storage<T>>,
+// Bit of the BPB reserved byte set by Windows while the volume is in use.
+const BPB_FLAG_DIRTY: u8 = 0x01;
+
+pub struct Fs <T: StorageIo, const N: usize = 1, C: TimeSource = NoTime> {
+    // only None once unmount has taken it
+    storage: RefCell<Option<Storage<T, N>>>,
     pub table: FatTable,
 
     sector_size: u32,
     sectors_in_cluster: u32,
     data_area_first_sector: u32,
     root_directory_first_sector: u32,
+    root_dir_sectors: u32,
     root_cluster: u32,
+    total_sectors: u32,
+    fat_size: u32,
+    fat_count: u32,
+    cluster_count: u32,
+    // offset of the reserved BPB byte holding the dirty flag
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    bpb_flags_offset: usize,
+    clean_mount: bool,
+    // file sizes past 4 GiB - 1 use the FAT+ bits of the entries
+    fat_plus: bool,
+    // true once the volume has been marked as in use on the device
+    #[cfg(not(feature = "fs_read_only"))]
+    volume_dirty: Cell<bool>,
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    time_source: Option<C>,
 }
 
-impl <T: StorageIo> Fs <T> {
-    pub fn new(storage_io: T) -> Result<Self, bool> {
+impl <T: StorageIo> Fs<T> {
+    // Mounts the volume with a single block cache.
+    pub fn new(storage_io: T) -> Result<Self, Error<T::Error>> {
+        Fs::with_cache(storage_io)
+    }
+}
+
+impl <T: StorageIo, const N: usize, C: TimeSource> Fs<T, N, C> {
+    // Mounts the volume caching `N` blocks of up to 4 KiB each, e.g.
+    // `let fs: Fs<_, 8> = Fs::with_cache(io)?`.
+    pub fn with_cache(storage_io: T) -> Result<Self, Error<T::Error>> {
         let mut storage = Storage::new(storage_io);
         let mut bpb = [0u8;512];
         storage.read(0, 0, &mut bpb)?;
@@ -49,6 +95,11 @@ impl <T: StorageIo> Fs <T> {
         let sectors_in_cluster = u32::from(bpb[13]);
         let reserved_sectors_count = u32::from(u16_from_bytes(&bpb[14..]));
         let num_fats = u32::from(bpb[16]);
+
+        if sector_size != storage.block_size() || sectors_in_cluster == 0 ||
+            !sectors_in_cluster.is_power_of_two() || reserved_sectors_count == 0 || num_fats == 0 {
+            return Err(Error::InvalidBpb);
+        }
         let root_entity_count = u32::from(u16_from_bytes(&bpb[17..])); // fat32 : 0
         let total_sectors_16 = u32::from(u16_from_bytes(&bpb[19..])); // fat32: 0
         let fat_size_16 = u32::from(u16_from_bytes(&bpb[22..])); // fat32: 0
@@ -67,9 +118,16 @@ impl <T: StorageIo> Fs <T> {
             u32_from_bytes(&bpb[32..])
         };
         // 1. Determine the count of sectors occupied by the root directory
-        let root_dir_sectors = ((root_entity_count * 32) + (sector_size - 1)) / sector_size;
-        // 2. Determine the count of sectors in the data region of the volume
-        let data_sec = total_sectors - (reserved_sectors_count + (num_fats * fat_size) + root_dir_sectors); 
+        let root_dir_sectors = (root_entity_count * 32).div_ceil(sector_size);
+        // 2. Determine the count of sectors in the data region of the volume, a
+        // corrupt BPB must not overflow or leave no room for data
+        let root_directory_first_sector = num_fats.checked_mul(fat_size)
+            .and_then(|fats| fats.checked_add(reserved_sectors_count))
+            .ok_or(Error::InvalidBpb)?;
+        let data_area_first_sector = root_directory_first_sector.checked_add(root_dir_sectors)
+            .filter(|&first| first < total_sectors)
+            .ok_or(Error::InvalidBpb)?;
+        let data_sec = total_sectors - data_area_first_sector;
 
         let count_of_clusters = data_sec / sectors_in_cluster;
 
@@ -77,17 +135,20 @@ impl <T: StorageIo> Fs <T> {
             //println!("FAT12");
             // fat 12 not supported
             #[cfg(feature = "fat12_disable")]
-            return Err(false); 
+            return Err(Error::UnsupportedFatType);
+            #[cfg(not(feature = "fat12_disable"))]
             FatType::Fat12
         } else if count_of_clusters < 65525 {
             //println!("FAT16");
             #[cfg(feature = "fat16_disable")]
-            return Err(false); 
+            return Err(Error::UnsupportedFatType);
+            #[cfg(not(feature = "fat16_disable"))]
             FatType::Fat16
         } else {     
             //println!("FAT32");
             #[cfg(feature = "fat32_disable")]
-            return Err(false); 
+            return Err(Error::UnsupportedFatType);
+            #[cfg(not(feature = "fat32_disable"))]
             FatType::Fat32
         };
         /*
@@ -98,46 +159,327 @@ impl <T: StorageIo> Fs <T> {
         println!("num_fats: {}", num_fats);
         println!("fat_size: {}", fat_size);
         */
-        let root_directory_first_sector = reserved_sectors_count + (num_fats * fat_size);
-        let data_area_first_sector = root_directory_first_sector + root_dir_sectors;
+        #[cfg(not(feature = "fs_read_only"))]
+        storage.fat_end_set(root_directory_first_sector);
         /*
         println!("root_directory_first_sector {}", root_directory_first_sector);
         println!("data_area_first_sector {}", data_area_first_sector);
         */
 
         let root_cluster = match fat_type {
+            #[cfg(not(feature = "fat32_disable"))]
             FatType::Fat32 => u32_from_bytes(&bpb[44..]),
+            #[allow(unreachable_patterns)]
             _ => 2,
         };
+
+        if !(2..=count_of_clusters + 1).contains(&root_cluster) {
+            return Err(Error::InvalidBpb);
+        }
         
+        let mut table = FatTable::new(fat_type, reserved_sectors_count, fat_size, sector_size, count_of_clusters, num_fats);
+
+        #[cfg(not(feature = "fat32_disable"))]
+        if let FatType::Fat32 = table.fat_type {
+            if !table.ext_flags_load(u16_from_bytes(&bpb[40..])) {
+                return Err(Error::InvalidBpb);
+            }
+
+            let fs_info_sector = u32::from(u16_from_bytes(&bpb[48..]));
+
+            if fs_info_sector != 0 && fs_info_sector < reserved_sectors_count {
+                table.fs_info_load(&mut storage, fs_info_sector)?;
+            }
+        }
+
+        let bpb_flags_offset = volume::ebpb_offset(table.fat_type) + volume::EBPB_FLAGS;
+
+        let clean_mount = bpb[bpb_flags_offset] & BPB_FLAG_DIRTY == 0 && table.volume_clean(&mut storage)?;
+
+        if !clean_mount {
+            // the FSInfo free count may be stale after an interrupted write
+            table.free_count_forget();
+        }
+
         Ok(Fs {
-            storage: RefCell::new(storage),
-            table: FatTable::new(fat_type, reserved_sectors_count, fat_size, sector_size),
+            storage: RefCell::new(Some(storage)),
+            table,
             sector_size,
             sectors_in_cluster,
             data_area_first_sector,
             root_directory_first_sector,
+            root_dir_sectors,
             root_cluster,
+            total_sectors,
+            fat_size,
+            fat_count: num_fats,
+            cluster_count: count_of_clusters,
+            bpb_flags_offset,
+            clean_mount,
+            fat_plus: false,
+            #[cfg(not(feature = "fs_read_only"))]
+            volume_dirty: Cell::new(false),
+            time_source: None,
         })
     }
 
-    pub fn root_dir(&self) -> Dir<T> {
-        match self.table.fat_type {
-            FatType::Fat32 => Dir::new(self, self.root_cluster),
-            _ => Dir::new(self, 0),
+    // Clock used to stamp created and written entries. Without one the time
+    // stamps are left empty. A reference to a clock owned elsewhere works as
+    // well, e.g. `let fs: Fs<_, 1, &Rtc> = Fs::with_cache(io)?`.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_time_source(&mut self, time_source: C) {
+        self.time_source = Some(time_source);
+    }
+
+    // A clock returning an invalid time leaves the stamps empty.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub(crate) fn now(&self) -> Option<DateTime> {
+        self.time_source.as_ref().map(|source| source.now()).filter(DateTime::is_valid)
+    }
+
+    // Stores file sizes up to 256 GiB - 1 on this volume the FAT+ way, see the
+    // `fat_plus` feature. Only enable it for volumes whose other users read FAT+,
+    // without it sizes past 4 GiB - 1 are refused and the FAT+ bits ignored.
+    #[cfg(feature = "fat_plus")]
+    pub fn set_fat_plus(&mut self, enabled: bool) {
+        self.fat_plus = enabled;
+    }
+
+    pub(crate) fn fat_plus(&self) -> bool {
+        self.fat_plus
+    }
+
+    // Describes the volume, the labels are read from the device on every call.
+    pub fn volume_info(&self) -> Result<VolumeInfo, Error<T::Error>> {
+        let mut bpb = [0u8; 512];
+        self.storage().read(0, 0, &mut bpb)?;
+
+        let mut oem_name = [0u8; 8];
+        oem_name.copy_from_slice(&bpb[3..11]);
+
+        let ebpb = &bpb[volume::ebpb_offset(self.table.fat_type)..];
+
+        let (serial, bpb_label) = if ebpb[volume::EBPB_BOOT_SIG] == volume::BOOT_SIGNATURE {
+            let mut label = [0u8; 11];
+            label.copy_from_slice(&ebpb[volume::EBPB_LABEL..volume::EBPB_LABEL + 11]);
+            (Some(u32_from_bytes(&ebpb[volume::EBPB_SERIAL..])), Some(label))
+        } else {
+            (None, None)
+        };
+
+        let root = self.root_dir();
+        let root_label = match root.iter().find(|e| e.is_volume_label()) {
+            Some(entry) => {
+                let mut label = [0u8; 11];
+                self.storage().read(entry.entry_sector, entry.entry_offset, &mut label)?;
+                Some(label)
+            },
+            None => None,
+        };
+
+        Ok(VolumeInfo {
+            fat_type: self.table.fat_type,
+            sector_size: self.sector_size,
+            cluster_size: self.sector_size * self.sectors_in_cluster,
+            total_sectors: self.total_sectors,
+            data_clusters: self.cluster_count,
+            fat_size: self.fat_size,
+            fat_count: self.fat_count,
+            serial,
+            oem_name,
+            bpb_label,
+            root_label,
+        })
+    }
+
+    // Number of free clusters. The FSInfo hint is used if it is present and the
+    // volume was cleanly unmounted, otherwise the FAT is scanned. With `recount`
+    // set the FAT is always scanned and a stale FSInfo sector rewritten.
+    pub fn free_clusters(&self, recount: bool) -> Result<u32, Error<T::Error>> {
+        if !recount {
+            if let Some(count) = self.table.free_count() {
+                return Ok(count);
+            }
+        }
+
+        let (free, _) = self.table.count(self.storage().deref_mut())?;
+
+        #[cfg(not(feature = "fs_read_only"))]
+        if recount && self.table.fs_info_stale(free) {
+            // FSInfo is only written with the volume marked as in use
+            self.dirty_mark()?;
+            self.table.free_count_set(free);
+            self.flush()?;
+            return Ok(free);
         }
+
+        self.table.free_count_load(free);
+        Ok(free)
+    }
+
+    // Counts free, bad and used clusters by scanning the whole FAT, nothing is
+    // written to the device.
+    pub fn stats(&self) -> Result<Stats, Error<T::Error>> {
+        let (free, bad) = self.table.count(self.storage().deref_mut())?;
+        self.table.free_count_load(free);
+
+        Ok(Stats {
+            cluster_size: self.sector_size * self.sectors_in_cluster,
+            total_clusters: self.cluster_count,
+            free_clusters: free,
+            bad_clusters: bad,
+            used_clusters: self.cluster_count - free - bad,
+        })
+    }
+
+    // False if the volume was left in use or with a hard error the last time,
+    // e.g. after a power loss, and should be checked.
+    pub fn was_cleanly_unmounted(&self) -> bool {
+        self.clean_mount
+    }
+
+    // Keeps the sectors of the FAT used for cluster lookups cached in preference
+    // to data and directory sectors, so walking a chain does not evict them.
+    pub fn set_fat_pinning(&self, pin: bool) {
+        let blocks = if pin { self.table.lookup_blocks() } else { 0..0 };
+        self.storage().pin(blocks);
+    }
+
+    pub fn root_dir(&self) -> Dir<'_, T, N, C> {
+        Dir::new(self, self.root_dir_cluster())
     }
 
     pub fn root_dir_cluster(&self) -> u32 {
         match self.table.fat_type {
+            #[cfg(not(feature = "fat32_disable"))]
             FatType::Fat32 => self.root_cluster,
+            #[allow(unreachable_patterns)]
             _ => 0,
         }
     }
 
+    // Writes the FSInfo hints and any cached block back to the device.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn flush(&self) -> Result<(), Error<T::Error>> {
+        let mut storage = self.storage();
+        self.table.flush(storage.deref_mut())?;
+        storage.flush()
+    }
+
+    // Selects when written blocks reach the device, write-back by default. Both
+    // write file data before the FAT and directory blocks referencing it.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_write_policy(&self, policy: WritePolicy) -> Result<(), Error<T::Error>> {
+        self.storage().set_policy(policy);
+        self.flush()
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn write_policy(&self) -> WritePolicy {
+        self.storage().policy()
+    }
+
+    // Sets the label in the boot sector and in the root directory, an empty
+    // label removes it.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_label(&self, label: &[u8]) -> Result<(), Error<T::Error>> {
+        let raw = if label.is_empty() {
+            None
+        } else {
+            Some(volume::label_raw(label).ok_or(Error::InvalidName)?)
+        };
+
+        self.dirty_mark()?;
+        self.root_dir().label_set(raw.as_ref())?;
+
+        {
+            let ebpb = volume::ebpb_offset(self.table.fat_type);
+            let mut storage = self.storage();
+            let mut signature = 0u8;
+            storage.read(0, ebpb + volume::EBPB_BOOT_SIG, core::slice::from_mut(&mut signature))?;
+
+            // without the extended boot signature the BPB has no label field
+            if signature == volume::BOOT_SIGNATURE {
+                storage.write(0, ebpb + volume::EBPB_LABEL, raw.as_ref().unwrap_or(volume::NO_NAME))?;
+            }
+        }
+
+        self.flush()
+    }
+
+    // Marks the volume as in use before its first modification. It is written to
+    // the device right away so an interrupted write is noticed on the next mount.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub(crate) fn dirty_mark(&self) -> Result<(), Error<T::Error>> {
+        if self.volume_dirty.get() {
+            return Ok(());
+        }
+
+        self.volume_state_write(true)?;
+        self.volume_dirty.set(true);
+        Ok(())
+    }
+
+    // Accepts the volume as consistent, e.g. once it was checked after a power
+    // loss. The dirty state is cleared right away and again on unmount.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn mark_clean(&mut self) -> Result<(), Error<T::Error>> {
+        self.flush()?;
+        self.volume_state_write(false)?;
+        self.volume_dirty.set(false);
+        self.clean_mount = true;
+        Ok(())
+    }
+
+    // Flushes everything and marks the volume as cleanly unmounted again. A volume
+    // that was not clean when mounted keeps its dirty state until `mark_clean`.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn shutdown(&self) -> Result<(), Error<T::Error>> {
+        self.flush()?;
+
+        if self.volume_dirty.get() && self.clean_mount {
+            self.volume_state_write(false)?;
+            self.volume_dirty.set(false);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn volume_state_write(&self, dirty: bool) -> Result<(), Error<T::Error>> {
+        let mut storage = self.storage();
+        let mut flags = 0u8;
+        storage.read(0, self.bpb_flags_offset, core::slice::from_mut(&mut flags))?;
+
+        if dirty {
+            flags |= BPB_FLAG_DIRTY;
+        } else {
+            flags &= !BPB_FLAG_DIRTY;
+        }
+
+        storage.write(0, self.bpb_flags_offset, &[flags])?;
+        self.table.volume_clean_set(storage.deref_mut(), !dirty)?;
+        storage.flush()
+    }
+
+    // Flushes all pending writes and gives the storage device back.
+    pub fn unmount(mut self) -> Result<T, Error<T::Error>> {
+        #[cfg(not(feature = "fs_read_only"))]
+        self.shutdown()?;
+
+        // dropping the rest finds no storage left to flush
+        let storage = self.storage.get_mut().take().expect("storage is only taken here");
+        Ok(storage.into_io())
+    }
+
+    // The block cache in front of the device.
+    pub fn storage(&self) -> RefMut<'_, Storage<T, N>> {
+        RefMut::map(self.storage.borrow_mut(), |storage| storage.as_mut().expect("storage used after unmount"))
+    }
+
     pub fn cluster_to_sector(&self, cluster: u32) -> u32 {
         if cluster != 0 {
-            self.data_area_first_sector + (cluster - self.root_cluster) * self.sectors_in_cluster
+            self.data_area_first_sector + (cluster - 2) * self.sectors_in_cluster
         } else {
             // If the parent directory is the root directory 
             // (which is statically allocated and doesn't have a cluster number),
@@ -146,3 +488,260 @@ impl <T: StorageIo> Fs <T> {
         }
     }
 }
+
+#[cfg(not(feature = "fs_read_only"))]
+impl <T: StorageIo, const N: usize, C: TimeSource> Drop for Fs<T, N, C> {
+    fn drop(&mut self) {
+        if self.storage.get_mut().is_some() {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "fs_read_only"))]
+mod tests {
+    use super::*;
+    use test_image::RamDisk;
+
+    fn block_byte(disk: &RamDisk, block: u32, offset: usize) -> u8 {
+        let mut data = [0u8; 512];
+        disk.read(block, &mut data).unwrap();
+        data[offset]
+    }
+
+    #[test]
+    fn unmount_flush_test() {
+        let fs: Fs<_, 4> = Fs::with_cache(test_image::format(2048, 2)).unwrap();
+        let block = fs.data_area_first_sector + 10;
+        fs.storage().write(block, 7, &[0x5A]).unwrap();
+        let disk = fs.unmount().unwrap();
+        assert_eq!(block_byte(&disk, block, 7), 0x5A);
+
+        // dropping the file system writes the cache back as well
+        {
+            let fs: Fs<_, 4> = Fs::with_cache(&disk).unwrap();
+            fs.storage().write(block, 8, &[0xA5]).unwrap();
+            assert_eq!(block_byte(&disk, block, 8), 0);
+        }
+        assert_eq!(block_byte(&disk, block, 8), 0xA5);
+    }
+
+    #[test]
+    fn corrupt_bpb_test() {
+        // patches the boot sector of a fresh FAT32 image
+        let mount = |patch: &dyn Fn(&mut [u8; 512])| {
+            let disk = test_image::format(140000, 2);
+            let mut bpb = [0u8; 512];
+            disk.read(0, &mut bpb).unwrap();
+            patch(&mut bpb);
+            disk.write(0, &bpb).unwrap();
+            Fs::new(disk).err()
+        };
+
+        assert_eq!(mount(&|_| ()), None);
+        // FAT count times FAT size overflows
+        assert_eq!(mount(&|bpb| { bpb[16] = 255; bpb[36..40].copy_from_slice(&0x0200_0000u32.to_le_bytes()); }), Some(Error::InvalidBpb));
+        // reserved sectors, FATs and root directory fill the whole volume
+        assert_eq!(mount(&|bpb| bpb[36..40].copy_from_slice(&70_000u32.to_le_bytes())), Some(Error::InvalidBpb));
+        // the root directory cluster lies outside of the data area
+        assert_eq!(mount(&|bpb| bpb[44..48].copy_from_slice(&0u32.to_le_bytes())), Some(Error::InvalidBpb));
+        assert_eq!(mount(&|bpb| bpb[44..48].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes())), Some(Error::InvalidBpb));
+    }
+
+    #[test]
+    fn volume_info_test() {
+        // type, cluster size, total sectors, data clusters, FAT size
+        let images = [
+            (test_image::format(2048, 2), FatType::Fat12, 1024, 2048, 1004, 3),
+            (test_image::format(32768, 4), FatType::Fat16, 2048, 32768, 8167, 32),
+            (test_image::format(140000, 2), FatType::Fat32, 1024, 140000, 69437, 547),
+        ];
+
+        for (disk, fat_type, cluster_size, total_sectors, data_clusters, fat_size) in images.iter() {
+            let fs = Fs::new(disk).unwrap();
+            let info = fs.volume_info().unwrap();
+            assert_eq!(info.fat_type(), *fat_type);
+            assert_eq!(info.sector_size(), 512);
+            assert_eq!(info.cluster_size(), *cluster_size);
+            assert_eq!(info.total_sectors(), *total_sectors);
+            assert_eq!(info.data_clusters(), *data_clusters);
+            assert_eq!(info.fat_size(), *fat_size);
+            assert_eq!(info.fat_count(), 2);
+            assert_eq!(info.serial(), Some(0x1234_5678));
+            assert_eq!(info.oem_name(), b"MSWIN4.1");
+            assert_eq!(info.bpb_label(), Some(&b"NO NAME"[..]));
+            assert_eq!(info.root_label(), None);
+
+            fs.set_label(b"Data 1").unwrap();
+            let info = fs.volume_info().unwrap();
+            assert_eq!(info.bpb_label(), Some(&b"DATA 1"[..]));
+            assert_eq!(info.root_label(), Some(&b"DATA 1"[..]));
+            assert_eq!(info.label(), Some(&b"DATA 1"[..]));
+
+            fs.set_label(b"").unwrap();
+            let info = fs.volume_info().unwrap();
+            assert_eq!(info.bpb_label(), Some(&b"NO NAME"[..]));
+            assert_eq!(info.root_label(), None);
+        }
+
+        // without the extended boot signature there is no serial or BPB label
+        let disk = test_image::format(2048, 2);
+        let mut bpb = [0u8; 512];
+        disk.read(0, &mut bpb).unwrap();
+        bpb[volume::ebpb_offset(FatType::Fat12) + volume::EBPB_BOOT_SIG] = 0;
+        disk.write(0, &bpb).unwrap();
+
+        let fs = Fs::new(&disk).unwrap();
+        fs.set_label(b"ROOT").unwrap();
+        let info = fs.volume_info().unwrap();
+        assert_eq!(info.serial(), None);
+        assert_eq!(info.bpb_label(), None);
+        assert_eq!(info.label(), Some(&b"ROOT"[..]));
+    }
+
+    #[test]
+    fn stats_test() {
+        let disk = test_image::format(32768, 4);
+        let fs = Fs::new(&disk).unwrap();
+        let cluster_size = 2048;
+        let total = fs.cluster_count;
+
+        let mut file = fs.root_dir().file_create(b"DATA.BIN").unwrap();
+        file.write(&[0x11; 3 * 2048]).unwrap();
+        drop(file);
+        fs.table.set(fs.storage().deref_mut(), 100, table::FatValue::Bad).unwrap();
+        fs.table.set(fs.storage().deref_mut(), 101, table::FatValue::Bad).unwrap();
+        fs.flush().unwrap();
+        disk.writes_take();
+
+        let stats = fs.stats().unwrap();
+        assert!(disk.writes_take().is_empty());
+        assert_eq!(stats.cluster_size, cluster_size);
+        assert_eq!(stats.total_clusters, total);
+        assert_eq!(stats.bad_clusters, 2);
+        assert_eq!(stats.used_clusters, 3);
+        assert_eq!(stats.free_clusters, total - 5);
+        assert_eq!(stats.total_bytes(), u64::from(total) * 2048);
+        assert_eq!(stats.used_bytes(), 3 * 2048);
+        assert_eq!(stats.free_bytes(), u64::from(total - 5) * 2048);
+        assert_eq!(fs.free_clusters(false).unwrap(), total - 5);
+    }
+
+    #[test]
+    fn fs_info_recount_test() {
+        // the FSInfo free count of the image is unknown
+        let disk = test_image::format(140000, 2);
+        let fs_info = |offset: usize| {
+            let mut data = [0u8; 512];
+            disk.read(1, &mut data).unwrap();
+            u32_from_bytes(&data[offset..])
+        };
+
+        let fs = Fs::new(&disk).unwrap();
+        let free = fs.cluster_count - 1;
+        disk.writes_take();
+
+        // counting keeps the result in memory only
+        assert_eq!(fs.stats().unwrap().free_clusters, free);
+        assert_eq!(fs.free_clusters(false).unwrap(), free);
+        assert!(disk.writes_take().is_empty());
+        assert_eq!(fs_info(488), 0xFFFF_FFFF);
+
+        // a recount rewrites FSInfo after marking the volume as in use
+        assert_eq!(fs.free_clusters(true).unwrap(), free);
+        let writes = disk.writes_take();
+        assert_eq!(writes.first(), Some(&0));
+        assert_eq!(writes.last(), Some(&1));
+        assert_eq!(fs_info(488), free);
+        assert!(volume_state(&disk, FatType::Fat32).0);
+
+        // nothing is written while FSInfo is up to date
+        assert_eq!(fs.free_clusters(true).unwrap(), free);
+        assert!(disk.writes_take().is_empty());
+
+        fs.unmount().unwrap();
+        assert!(!volume_state(&disk, FatType::Fat32).0);
+        assert_eq!(Fs::new(&disk).unwrap().free_clusters(false).unwrap(), free);
+    }
+
+    // BPB dirty flag and the FAT entry 1 clean bits of every copy, FAT12 has no
+    // bits in entry 1
+    fn volume_state(disk: &RamDisk, fat_type: FatType) -> (bool, Option<[u32; 2]>) {
+        let mut bpb = [0u8; 512];
+        disk.read(0, &mut bpb).unwrap();
+        let dirty = bpb[volume::ebpb_offset(fat_type) + volume::EBPB_FLAGS] & BPB_FLAG_DIRTY != 0;
+
+        let reserved = u32::from(u16_from_bytes(&bpb[14..]));
+        let fat_size = match u16_from_bytes(&bpb[22..]) {
+            0 => u32_from_bytes(&bpb[36..]),
+            size => u32::from(size),
+        };
+        let entry_1 = |fat: u32| {
+            let mut data = [0u8; 512];
+            disk.read(reserved + fat * fat_size, &mut data).unwrap();
+            match fat_type {
+                FatType::Fat16 => u32::from(u16_from_bytes(&data[2..])) & 0xC000,
+                FatType::Fat32 => u32_from_bytes(&data[4..]) & 0x0C00_0000,
+                _ => 0,
+            }
+        };
+        let bits = match fat_type {
+            FatType::Fat12 => None,
+            _ => Some([entry_1(0), entry_1(1)]),
+        };
+        (dirty, bits)
+    }
+
+    #[test]
+    fn dirty_flag_test() {
+        let images = [
+            (test_image::format(2048, 2), FatType::Fat12, None),
+            (test_image::format(32768, 4), FatType::Fat16, Some((0xC000, 0x4000))),
+            (test_image::format(140000, 2), FatType::Fat32, Some((0x0C00_0000, 0x0400_0000))),
+        ];
+
+        for (disk, fat_type, bits) in images.iter() {
+            let clean = (false, bits.map(|(clean, _)| [clean; 2]));
+            let dirty = (true, bits.map(|(_, dirty)| [dirty; 2]));
+
+            let fs = Fs::new(disk).unwrap();
+            assert_eq!(fs.table.fat_type, *fat_type);
+            assert!(fs.was_cleanly_unmounted());
+            fs.root_dir().iter().count();
+            assert_eq!(volume_state(disk, *fat_type), clean);
+
+            // the first write marks the volume as in use on the device right away
+            fs.root_dir().file_create(b"A.TXT").unwrap();
+            assert_eq!(volume_state(disk, *fat_type), dirty);
+            fs.root_dir().file_create(b"B.TXT").unwrap();
+            fs.unmount().unwrap();
+            assert_eq!(volume_state(disk, *fat_type), clean);
+
+            // power loss before the unmount
+            let fs = Fs::new(disk).unwrap();
+            fs.root_dir().file_create(b"C.TXT").unwrap();
+            fs.flush().unwrap();
+            core::mem::forget(fs);
+            assert_eq!(volume_state(disk, *fat_type), dirty);
+
+            // stays dirty over an unmount until it is marked clean
+            let fs = Fs::new(disk).unwrap();
+            assert!(!fs.was_cleanly_unmounted());
+            fs.root_dir().file_create(b"D.TXT").unwrap();
+            fs.unmount().unwrap();
+            assert_eq!(volume_state(disk, *fat_type), dirty);
+
+            let mut fs = Fs::new(disk).unwrap();
+            assert!(!fs.was_cleanly_unmounted());
+            fs.mark_clean().unwrap();
+            assert!(fs.was_cleanly_unmounted());
+            assert_eq!(volume_state(disk, *fat_type), clean);
+            fs.root_dir().file_create(b"E.TXT").unwrap();
+            assert_eq!(volume_state(disk, *fat_type), dirty);
+            fs.unmount().unwrap();
+            assert_eq!(volume_state(disk, *fat_type), clean);
+            assert!(Fs::new(disk).unwrap().was_cleanly_unmounted());
+        }
+    }
+}
@@ -4,15 +4,15 @@ pub struct Path<'a> {
 }
 
 impl <'a> Path<'a> {
-    pub fn new(path: &[u8]) -> Path {
+    pub fn new(path: &[u8]) -> Path<'_> {
         Path {
-            path: path,
+            path,
             pos: 0,
         }
     }
 
     pub fn is_end(&self) -> bool {
-        self.pos + 1 >= self.path.len()
+        self.pos >= self.path.len()
     }
 }
 
@@ -24,7 +24,7 @@ impl <'a>Iterator for Path<'a> {
         let mut stop_pos = self.pos;
 
         while stop_pos < self.path.len() {
-            if self.path[stop_pos] == b'/' || self.path[stop_pos] == b'\\' {
+            if is_separator(self.path[stop_pos]) {
                 break;
             }
 
@@ -38,4 +38,23 @@ impl <'a>Iterator for Path<'a> {
             Some(&self.path[start_pos..stop_pos])
         }
     }
-}
\ No newline at end of file
+}
+fn is_separator(byte: u8) -> bool {
+    byte == b'/' || byte == b'\\'
+}
+
+// Splits a path into the path of the parent directory and the last component.
+pub fn split_last(path: &[u8]) -> (&[u8], &[u8]) {
+    let mut end = path.len();
+
+    while end > 0 && is_separator(path[end - 1]) {
+        end -= 1;
+    }
+
+    let path = &path[..end];
+
+    match path.iter().rposition(|&b| is_separator(b)) {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => (&path[..0], path),
+    }
+}
@@ -0,0 +1,185 @@
+// Generation of the 8.3 alias for a long file name, following the basis name
+// and numeric tail rules Windows uses:
+//
+//   "Readme.txt"        -> "README.TXT" (fits, no tail)
+//   "Long File Name.md" -> "LONGFI~1.MD", "LONGFI~2.MD" ... "LONGFI~4.MD"
+//   fifth and later     -> "LO51D4~1.MD", two chars of the base and the checksum of the long name
+
+// Punctuation allowed in a short name besides letters and digits.
+const SFN_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+// Number of plain ~N tails tried before switching to checksum based names.
+const PLAIN_TAILS: u32 = 4;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+pub fn is_valid_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SFN_SPECIAL.contains(&byte)
+}
+
+// Uppercases a character of the long name, replacing the ones not allowed in
+// a short name with '_'. The second value is true if the character was replaced.
+fn convert(byte: u8) -> (u8, bool) {
+    if is_valid_char(byte) {
+        (byte.to_ascii_uppercase(), false)
+    } else {
+        (b'_', true)
+    }
+}
+
+// Checksum of the long name Windows NT and later put into hashed aliases. The
+// arithmetic wraps like the 32 bit C code it was taken from, and the nibbles
+// end up in reverse order.
+fn hash(name: &[u8]) -> u16 {
+    let checksum = name.iter().fold(0u16, |sum, &b| sum.wrapping_mul(0x25).wrapping_add(u16::from(b)));
+    let mut temp = i32::from(checksum).wrapping_mul(314_159_269).wrapping_abs();
+    let quotient = ((i64::from(temp).wrapping_mul(1_152_921_497)) as u64 >> 60) as i32;
+    temp = temp.wrapping_sub(quotient.wrapping_mul(1_000_000_007));
+    let checksum = temp as u16;
+
+    ((checksum & 0xf000) >> 12) | ((checksum & 0x0f00) >> 4) | ((checksum & 0x00f0) << 4) | ((checksum & 0x000f) << 12)
+}
+
+// Builds the padded 11 byte basis name for `name`. The second value is true if
+// the basis name does not represent the long name exactly, so the entry needs
+// a numeric tail.
+pub fn basis(name: &[u8]) -> ([u8; 11], bool) {
+    let mut raw = [b' '; 11];
+    let mut lossy = false;
+
+    let start = name.iter().position(|&b| b != b'.' && b != b' ').unwrap_or(name.len());
+    let name_stripped = &name[start..];
+
+    let (base, ext) = match name_stripped.iter().rposition(|&b| b == b'.') {
+        Some(dot) => (&name_stripped[..dot], &name_stripped[dot + 1..]),
+        None => (name_stripped, &name_stripped[..0]),
+    };
+
+    for (i, &b) in base.iter().filter(|&&b| b != b' ' && b != b'.').take(8).enumerate() {
+        let (c, replaced) = convert(b);
+        lossy |= replaced;
+        raw[i] = c;
+    }
+
+    for (i, &b) in ext.iter().filter(|&&b| b != b' ').take(3).enumerate() {
+        let (c, replaced) = convert(b);
+        lossy |= replaced;
+        raw[8 + i] = c;
+    }
+
+    let mut buf = [0u8; 12];
+    let len = display(&raw, &mut buf);
+
+    (raw, lossy || !buf[..len].eq_ignore_ascii_case(name))
+}
+
+// Returns the `n`-th (starting from 1) alias candidate for the basis name of `name`.
+pub fn with_tail(basis: &[u8; 11], name: &[u8], n: u32) -> [u8; 11] {
+    let mut raw = *basis;
+    let mut base_len = raw[..8].iter().position(|&b| b == b' ').unwrap_or(8);
+
+    let tail = if n <= PLAIN_TAILS {
+        n
+    } else {
+        let hash = hash(name);
+        base_len = core::cmp::min(base_len, 2);
+
+        for i in 0..4 {
+            raw[base_len] = HEX_DIGITS[usize::from((hash >> (12 - 4 * i)) & 0x0f)];
+            base_len += 1;
+        }
+
+        n - PLAIN_TAILS
+    };
+
+    let mut digits = [0u8; 10];
+    let mut digits_len = 0;
+    let mut value = tail;
+
+    while value != 0 || digits_len == 0 {
+        digits[digits_len] = b'0' + (value % 10) as u8;
+        digits_len += 1;
+        value /= 10;
+    }
+
+    let tail_start = core::cmp::min(base_len, 8 - (digits_len + 1));
+    raw[tail_start] = b'~';
+
+    for i in 0..digits_len {
+        raw[tail_start + 1 + i] = digits[digits_len - 1 - i];
+    }
+
+    for b in raw[tail_start + 1 + digits_len..8].iter_mut() {
+        *b = b' ';
+    }
+
+    raw
+}
+
+// Formats a padded 8.3 name the same way `DirEntry::sfn_buf` stores it.
+pub fn display(raw: &[u8; 11], buf: &mut [u8; 12]) -> usize {
+    let mut len = 0;
+
+    for &b in raw.iter().take(8) {
+        if b == b' ' {
+            break;
+        }
+
+        buf[len] = b;
+        len += 1;
+    }
+
+    if raw[8] != b' ' {
+        buf[len] = b'.';
+        len += 1;
+
+        for &b in raw.iter().skip(8) {
+            if b == b' ' {
+                break;
+            }
+
+            buf[len] = b;
+            len += 1;
+        }
+    }
+
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basis_fits_test() {
+        assert_eq!(basis(b"README.TXT"), (*b"README  TXT", false));
+        assert_eq!(basis(b"readme.txt"), (*b"README  TXT", false));
+        assert_eq!(basis(b"Makefile"), (*b"MAKEFILE   ", false));
+    }
+
+    #[test]
+    fn basis_lossy_test() {
+        assert_eq!(basis(b"Long File Name.markdown"), (*b"LONGFILEMAR", true));
+        assert_eq!(basis(b"jquery.min.js"), (*b"JQUERYMIJS ", true));
+        assert_eq!(basis(b".bashrc"), (*b"BASHRC     ", true));
+        assert_eq!(basis(b"a+b.txt"), (*b"A_B     TXT", true));
+    }
+
+    #[test]
+    fn tail_test() {
+        let (raw, _) = basis(b"Long File Name.md");
+        assert_eq!(with_tail(&raw, b"Long File Name.md", 1), *b"LONGFI~1MD ");
+        assert_eq!(with_tail(&raw, b"Long File Name.md", 4), *b"LONGFI~4MD ");
+
+        let (raw, _) = basis(b"ab.c");
+        assert_eq!(with_tail(&raw, b"ab.c", 3), *b"AB~3    C  ");
+
+        let hashed = with_tail(&raw, b"ab.c", 5);
+        assert_eq!(&hashed[..2], b"AB");
+        assert_eq!(&hashed[6..], b"~1C  ");
+        assert_ne!(hashed, with_tail(&raw, b"ab.c", 6));
+
+        let (raw, _) = basis(b"TextFile.Mine.txt");
+        assert_eq!(with_tail(&raw, b"TextFile.Mine.txt", 5), *b"TEE50A~1TXT");
+        assert_eq!(with_tail(&raw, b"TextFile.Mine.txt", 6), *b"TEE50A~2TXT");
+    }
+}
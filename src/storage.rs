@@ -1,87 +1,396 @@
 use super::StorageIo;
+use super::error::Error;
+use core::ops::Range;
 
-const BLOCK_MAX_SIZE: usize = 4096;
+pub(crate) const BLOCK_MAX_SIZE: usize = 4096;
 const BLOCK_MIN_SIZE: usize = 512;
 
-pub struct Storage<T: StorageIo> {
-    io: T,
-    cached_block: u32,
+// When written blocks reach the device.
+#[cfg(not(feature = "fs_read_only"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    // Blocks stay in the cache until they are evicted or flushed.
+    WriteBack,
+    // File data is written right away, the FAT and directory blocks referencing
+    // it at the end of every operation.
+    WriteThrough,
+}
+
+// What a dirty block holds. Blocks are written back in this order, so nothing on
+// the device points to data that has not been written yet.
+#[cfg(not(feature = "fs_read_only"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum BlockKind {
+    Data,
+    Fat,
+    Directory,
+}
+
+#[cfg(not(feature = "fs_read_only"))]
+const FLUSH_ORDER: [BlockKind; 3] = [BlockKind::Data, BlockKind::Fat, BlockKind::Directory];
+
+// One cached block, `block` is u32::MAX while the entry is empty.
+struct CacheEntry {
+    block: u32,
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    dirty: bool,
+    #[cfg(not(feature = "fs_read_only"))]
+    kind: BlockKind,
+    // value of the access counter when the block was last used
+    used: u64,
     data: [u8; BLOCK_MAX_SIZE],
+}
+
+const CACHE_ENTRY_EMPTY: CacheEntry = CacheEntry {
+    block: u32::MAX,
+    dirty: false,
+    #[cfg(not(feature = "fs_read_only"))]
+    kind: BlockKind::Data,
+    used: 0,
+    data: [0u8; BLOCK_MAX_SIZE],
+};
+
+// Caches `N` blocks and evicts the least recently used one.
+pub struct Storage<T: StorageIo, const N: usize = 1> {
+    io: T,
+    entries: [CacheEntry; N],
+    accesses: u64,
+    // blocks evicted only when every other entry holds one of them as well
+    pinned: Range<u32>,
+    // blocks below it are the boot sector, FSInfo and FATs
+    #[cfg(not(feature = "fs_read_only"))]
+    fat_end: u32,
+    #[cfg(not(feature = "fs_read_only"))]
+    policy: WritePolicy,
     block_size: usize,
     block_count: u32,
-    dirty: bool,
 }
 
 pub trait StorageRead {
-    fn read(&mut self, block: u32, offset: usize, buf: &mut [u8]) -> Result<(), bool>;
+    type Error;
+
+    fn read(&mut self, block: u32, offset: usize, buf: &mut [u8]) -> Result<(), Error<Self::Error>>;
 }
 
-pub trait StorageWrite {
-    fn write(&mut self, block: u32, offset: usize, buf: &[u8]) -> Result<(), bool>;
-    fn flush(&mut self) -> Result<(), bool>;
+pub trait StorageWrite: StorageRead {
+    fn write(&mut self, block: u32, offset: usize, buf: &[u8]) -> Result<(), Error<Self::Error>>;
+    fn flush(&mut self) -> Result<(), Error<Self::Error>>;
 }
 
-impl <T: StorageIo> Storage <T> {
+impl <T: StorageIo, const N: usize> Storage<T, N> {
     pub fn new(io: T) -> Self {
         let block_size = io.block_size() as usize;
         let block_count = io.block_count();
 
-        assert!((block_size as usize) >= BLOCK_MIN_SIZE);
-        assert!((block_size as usize) <= BLOCK_MAX_SIZE);
-        assert!(((block_size as usize) % 512) == 0);
-        
+        assert!(block_size >= BLOCK_MIN_SIZE);
+        assert!(block_size <= BLOCK_MAX_SIZE);
+        assert!(block_size.is_multiple_of(512));
+        assert!(N > 0);
+
         Storage {
             io,
-            cached_block: core::u32::MAX,
-            data: [0u8; BLOCK_MAX_SIZE],
+            entries: [CACHE_ENTRY_EMPTY; N],
+            accesses: 0,
+            pinned: 0..0,
+            #[cfg(not(feature = "fs_read_only"))]
+            fat_end: 0,
+            #[cfg(not(feature = "fs_read_only"))]
+            policy: WritePolicy::WriteBack,
             block_size,
             block_count,
-            dirty: false,
         }
     }
 
-    fn sync(&mut self, block: u32) -> Result<(), bool> {
+    // Prefers keeping `blocks` cached over any other block, an empty range pins
+    // nothing.
+    pub fn pin(&mut self, blocks: Range<u32>) {
+        self.pinned = blocks;
+    }
+
+    // Blocks written below `fat_end` are flushed as FAT blocks, the other ones as
+    // directory blocks unless they are written with `write_data`.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn fat_end_set(&mut self, fat_end: u32) {
+        self.fat_end = fat_end;
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn set_policy(&mut self, policy: WritePolicy) {
+        self.policy = policy;
+    }
+
+    #[cfg(all(test, not(feature = "fs_read_only")))]
+    pub(crate) fn io(&self) -> &T {
+        &self.io
+    }
+
+    pub fn into_io(self) -> T {
+        self.io
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size as u32
+    }
+
+    // Reads whole blocks straight into `buf`, bypassing the cache. Dirty cached
+    // copies of them are written back first.
+    pub fn read_blocks(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Error<T::Error>> {
+        let count = (buf.len() / self.block_size) as u32;
+        assert!(buf.len().is_multiple_of(self.block_size));
+
+        if block >= self.block_count || count > self.block_count - block {
+            return Err(Error::OutOfBounds);
+        }
+
+        #[cfg(not(feature = "fs_read_only"))]
+        for i in 0..N {
+            if (block..block + count).contains(&self.entries[i].block) {
+                self.entry_flush_ordered(i)?;
+            }
+        }
+
+        self.io.read_blocks(block, buf).map_err(Error::Io)
+    }
+
+    // Writes whole blocks straight from `buf`, a cached copy of one of them is
+    // dropped as it is overwritten anyway.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn write_blocks(&mut self, block: u32, buf: &[u8]) -> Result<(), Error<T::Error>> {
+        let count = (buf.len() / self.block_size) as u32;
+        assert!(buf.len().is_multiple_of(self.block_size));
+
+        if block >= self.block_count || count > self.block_count - block {
+            return Err(Error::OutOfBounds);
+        }
+
+        for entry in self.entries.iter_mut() {
+            if (block..block + count).contains(&entry.block) {
+                *entry = CACHE_ENTRY_EMPTY;
+            }
+        }
+
+        self.io.write_blocks(block, buf).map_err(Error::Io)
+    }
+
+    // Index of the entry holding `block`, reading it into the least recently used
+    // entry if it is not cached yet.
+    fn sync(&mut self, block: u32) -> Result<usize, Error<T::Error>> {
         if block >= self.block_count {
-            return Err(false);
+            return Err(Error::OutOfBounds);
+        }
+
+        self.accesses += 1;
+
+        if let Some(i) = self.entries.iter().position(|e| e.block == block) {
+            self.entries[i].used = self.accesses;
+            return Ok(i);
+        }
+
+        // empty entries have never been used and go first
+        let pinned = &self.pinned;
+        let i = (0..N).min_by_key(|&i| (pinned.contains(&self.entries[i].block), self.entries[i].used)).unwrap_or(0);
+
+        #[cfg(not(feature = "fs_read_only"))]
+        self.entry_flush_ordered(i)?;
+
+        let entry = &mut self.entries[i];
+        entry.block = u32::MAX;
+        self.io.read(block, &mut entry.data[..self.block_size]).map_err(Error::Io)?;
+        entry.block = block;
+        entry.used = self.accesses;
+        Ok(i)
+    }
+
+    // Writes file data to a block. Unlike the FAT and directory blocks it goes
+    // to the device right away with the write-through policy.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn write_data(&mut self, block: u32, offset: usize, buf: &[u8]) -> Result<(), Error<T::Error>> {
+        let i = self.entry_write(block, offset, buf, BlockKind::Data)?;
+
+        if self.policy == WritePolicy::WriteThrough {
+            self.entry_flush(i)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entry_write(&mut self, block: u32, offset: usize, buf: &[u8], kind: BlockKind) -> Result<usize, Error<T::Error>> {
+        assert!(buf.len() <= self.block_size - offset);
+
+        let i = self.sync(block)?;
+        let entry = &mut self.entries[i];
+        // a block holding several kinds is written with the latest of them
+        entry.kind = if entry.dirty { core::cmp::max(entry.kind, kind) } else { kind };
+        entry.dirty = true;
+        let offset_end = offset + buf.len();
+        entry.data[offset..offset_end].copy_from_slice(buf);
+        Ok(i)
+    }
+
+    // Writes back entry `i` after all dirty blocks that have to reach the
+    // device before it.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entry_flush_ordered(&mut self, i: usize) -> Result<(), Error<T::Error>> {
+        if !self.entries[i].dirty {
+            return Ok(());
+        }
+
+        let last = self.entries[i].kind;
+
+        for kind in FLUSH_ORDER.iter().copied().take_while(|&kind| kind < last) {
+            self.kind_flush(kind)?;
+        }
+
+        self.entry_flush(i)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn kind_flush(&mut self, kind: BlockKind) -> Result<(), Error<T::Error>> {
+        for i in 0..N {
+            if self.entries[i].kind == kind {
+                self.entry_flush(i)?;
+            }
         }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn entry_flush(&mut self, i: usize) -> Result<(), Error<T::Error>> {
+        let entry = &mut self.entries[i];
 
-        if block != self.cached_block {
-            self.flush()?;
-            self.io.read(block, &mut self.data[..self.block_size])?;
-            self.cached_block = block;
+        if entry.dirty {
+            self.io.write(entry.block, &entry.data[..self.block_size]).map_err(Error::Io)?;
+            entry.dirty = false;
         }
         Ok(())
     }
 }
 
-impl <T: StorageIo> StorageRead for Storage <T> {
-    fn read(&mut self, block: u32, offset: usize, buf: &mut [u8]) -> Result<(), bool> {
+impl <T: StorageIo, const N: usize> StorageRead for Storage<T, N> {
+    type Error = T::Error;
+
+    fn read(&mut self, block: u32, offset: usize, buf: &mut [u8]) -> Result<(), Error<T::Error>> {
         assert!(buf.len() <= self.block_size - offset);
         //println!("read: 0x{:x}", block * 512 + offset as u32);
-        self.sync(block)?;
+        let i = self.sync(block)?;
         let offset_end = offset + buf.len();
-        buf[..].copy_from_slice(&self.data[offset..offset_end]);
+        buf[..].copy_from_slice(&self.entries[i].data[offset..offset_end]);
         Ok(())
     }
 }
 
-impl <T: StorageIo> StorageWrite for Storage <T> {
-    fn write(&mut self, block: u32, offset: usize, buf: &[u8]) -> Result<(), bool> {
-        assert!(buf.len() <= self.block_size - offset);
-
-        self.sync(block)?;
-        self.dirty = true;
-        let offset_end = offset + buf.len();
-        self.data[offset..offset_end].copy_from_slice(&buf[..]);
+#[cfg(not(feature = "fs_read_only"))]
+impl <T: StorageIo, const N: usize> StorageWrite for Storage<T, N> {
+    fn write(&mut self, block: u32, offset: usize, buf: &[u8]) -> Result<(), Error<T::Error>> {
+        let kind = if block < self.fat_end { BlockKind::Fat } else { BlockKind::Directory };
+        self.entry_write(block, offset, buf, kind)?;
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<(), bool> {
-        if self.dirty {
-            self.io.write(self.cached_block, &self.data[..self.block_size])?;
-            self.dirty = false;
+    fn flush(&mut self) -> Result<(), Error<T::Error>> {
+        for kind in FLUSH_ORDER {
+            self.kind_flush(kind)?;
         }
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::RamDisk;
+
+    #[test]
+    fn lru_test() {
+        let mut storage: Storage<RamDisk, 2> = Storage::new(RamDisk::new(16));
+        let mut byte = [0u8];
+
+        for block in [1, 2, 1, 3, 1] {
+            storage.read(block, 0, &mut byte).unwrap();
+        }
+
+        // 3 evicted 2, the least recently used block
+        assert_eq!(storage.io.requests_take(), 3);
+        storage.read(2, 0, &mut byte).unwrap();
+        assert_eq!(storage.io.requests_take(), 1);
+
+        // a pinned block survives as long as another entry can go
+        storage.pin(5..6);
+        for block in [5, 6, 7, 8, 5] {
+            storage.read(block, 0, &mut byte).unwrap();
+        }
+        assert_eq!(storage.io.requests_take(), 4);
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    #[test]
+    fn dirty_entries_test() {
+        let mut storage: Storage<RamDisk, 2> = Storage::new(RamDisk::new(16));
+        storage.write(1, 0, &[0xAA]).unwrap();
+        storage.write(2, 0, &[0xBB]).unwrap();
+        assert_eq!(storage.io.requests_take(), 2);
+
+        // evicted dirty blocks are written back, clean ones are just dropped
+        storage.read(3, 0, &mut [0u8]).unwrap();
+        storage.read(4, 0, &mut [0u8]).unwrap();
+        assert_eq!(storage.io.requests_take(), 4);
+        storage.read(5, 0, &mut [0u8]).unwrap();
+        assert_eq!(storage.io.requests_take(), 1);
+        storage.flush().unwrap();
+        assert_eq!(storage.io.requests_take(), 0);
+
+        let io = storage.into_io();
+        let mut block = [0u8; 512];
+        io.read(1, &mut block).unwrap();
+        assert_eq!(block[0], 0xAA);
+        io.read(2, &mut block).unwrap();
+        assert_eq!(block[0], 0xBB);
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    #[test]
+    fn flush_order_test() {
+        let mut storage: Storage<RamDisk, 4> = Storage::new(RamDisk::new(64));
+        storage.fat_end_set(10);
+        storage.write(20, 0, &[1]).unwrap();
+        storage.write(5, 0, &[2]).unwrap();
+        storage.write_data(30, 0, &[3]).unwrap();
+        storage.write_data(31, 0, &[4]).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.io.writes_take(), [30, 31, 5, 20]);
+
+        // evicting the FAT block writes the data referenced by it first
+        storage.write(5, 0, &[5]).unwrap();
+        storage.write_data(30, 0, &[6]).unwrap();
+        for block in [40, 41, 42] {
+            storage.read(block, 0, &mut [0u8]).unwrap();
+        }
+        assert_eq!(storage.io.writes_take(), [30, 5]);
+
+        // a data block reused for directory entries is written with them
+        storage.write_data(32, 0, &[7]).unwrap();
+        storage.write(32, 1, &[8]).unwrap();
+        storage.write(6, 0, &[9]).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.io.writes_take(), [6, 32]);
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    #[test]
+    fn write_through_test() {
+        let mut storage: Storage<RamDisk, 4> = Storage::new(RamDisk::new(64));
+        storage.fat_end_set(10);
+        storage.set_policy(WritePolicy::WriteThrough);
+        storage.write(5, 0, &[1]).unwrap();
+        storage.write_data(30, 0, &[2]).unwrap();
+        storage.write_data(30, 1, &[3]).unwrap();
+        assert_eq!(storage.io.writes_take(), [30, 30]);
+        storage.flush().unwrap();
+        assert_eq!(storage.io.writes_take(), [5]);
+    }
+}
@@ -1,7 +1,30 @@
 pub trait StorageIo {
+    type Error;
+
     fn block_size(&self) -> u32;
     fn block_count(&self) -> u32;
-    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), bool>;
+    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), Self::Error>;
     #[cfg(not(feature = "fs_read_only"))]
-    fn write(&self, block: u32, data: &[u8]) -> Result<(), bool>;
-}
\ No newline at end of file
+    fn write(&self, block: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    // Reads `data.len() / block_size()` consecutive blocks starting at `block`.
+    // Devices able to transfer several blocks at once should override it.
+    fn read_blocks(&self, block: u32, data: &mut [u8]) -> Result<(), Self::Error> {
+        let block_size = self.block_size() as usize;
+
+        for (i, chunk) in data.chunks_exact_mut(block_size).enumerate() {
+            self.read(block + i as u32, chunk)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn write_blocks(&self, block: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let block_size = self.block_size() as usize;
+
+        for (i, chunk) in data.chunks_exact(block_size).enumerate() {
+            self.write(block + i as u32, chunk)?;
+        }
+        Ok(())
+    }
+}
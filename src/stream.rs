@@ -1,40 +1,94 @@
 use super::Fs;
 use super::storage_io::StorageIo;
+use super::date_time::{NoTime, TimeSource};
 use crate::storage::StorageRead;
+#[cfg(not(feature = "fs_read_only"))]
 use crate::storage::StorageWrite;
 use super::table::FatValue;
+use super::error::Error;
+use super::cluster_map::{self, ClusterMap};
 use core::ops::DerefMut;
 
 pub enum StreamPos {
-    Start(u32),
-    Current(i32),
-    End(i32),
+    Start(u64),
+    Current(i64),
+    End(i64),
 }
 
-pub struct Stream<'a, T: StorageIo> {
-    pub fs: &'a Fs<T>,
+// What a stream holds. File data is written with the write policy and reaches
+// the device before the FAT and directory blocks referencing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Data,
+    Directory,
+}
+
+// Consecutive clusters found for the stream. They are linked into the chain only
+// after their content has been written, so even a single cache entry never puts a
+// FAT block on the device that points to data missing there.
+#[cfg(not(feature = "fs_read_only"))]
+#[derive(Clone, Copy)]
+struct Link {
+    // the end of the chain, None for the first cluster of a file
+    prev: Option<u32>,
+    first: u32,
+    count: u32,
+}
+
+pub struct Stream<'a, T: StorageIo, const N: usize = 1, C: TimeSource = NoTime> {
+    pub fs: &'a Fs<T, N, C>,
     first_cluster: u32,
     current_cluster: u32,
+    // position of current_cluster in the chain
+    cluster_index: u32,
     sector_in_cluster: u32,
     offset_in_sector: usize,
-    global_offset: u32,
+    global_offset: u64,
     lenear: bool,
+    #[cfg_attr(feature = "fs_read_only", allow(dead_code))]
+    kind: StreamKind,
+    cluster_map: Option<ClusterMap<'a>>,
 }
 
-impl <'a, T: StorageIo> Stream<'a, T> {
-    pub fn new(fs: &Fs<T>, cluster: u32, lenear: bool) -> Stream<T> {
+impl <'a, T: StorageIo, const N: usize, C: TimeSource> Stream<'a, T, N, C> {
+    pub fn new(fs: &Fs<T, N, C>, cluster: u32, lenear: bool, kind: StreamKind) -> Stream<'_, T, N, C> {
         Stream {
             fs,
             first_cluster: cluster,
             current_cluster: cluster,
+            cluster_index: 0,
             sector_in_cluster: 0,
             offset_in_sector: 0,
             global_offset: 0,
             lenear,
+            kind,
+            cluster_map: None,
         }
     }
 
-    fn sync(&mut self) -> Result<(), bool> {
+    // Maps the cluster chain into `runs` so that seeks do not walk the FAT. Returns
+    // the number of runs used, fails with BufferTooSmall if they do not fit.
+    pub fn cluster_map_set(&mut self, runs: &'a mut [[u32; 2]]) -> Result<usize, Error<T::Error>> {
+        self.cluster_map = None;
+        let mut storage = self.fs.storage();
+        let map = ClusterMap::new(runs, &self.fs.table, storage.deref_mut(), self.first_cluster)?;
+        let run_count = map.run_count();
+        self.cluster_map = Some(map);
+        Ok(run_count)
+    }
+
+    // Number of runs of consecutive clusters the chain is made of.
+    pub fn cluster_runs(&self) -> Result<usize, Error<T::Error>> {
+        let mut storage = self.fs.storage();
+        cluster_map::runs_count(&self.fs.table, storage.deref_mut(), self.first_cluster)
+    }
+
+    // Has to be called when clusters are removed from the chain.
+    pub fn cluster_map_clear(&mut self) {
+        self.cluster_map = None;
+    }
+
+    fn sync(&mut self) -> Result<(), Error<T::Error>> {
         /*
         println!("offset_in_sector: {}", self.offset_in_sector);
         println!("self.fs.sectors_in_cluster: {}", self.fs.sectors_in_cluster);
@@ -43,13 +97,16 @@ impl <'a, T: StorageIo> Stream<'a, T> {
         if self.offset_in_sector as u32 >= self.fs.sector_size {
             if !self.lenear {
                 if self.sector_in_cluster + 1 >= self.fs.sectors_in_cluster {
-                    let mut storage = self.fs.storage.borrow_mut();
+                    let mut storage = self.fs.storage();
                     let storage_mut = storage.deref_mut();
 
                     match self.fs.table.get(storage_mut, self.current_cluster)? {
-                        FatValue::Next(next) => self.current_cluster = next,
-                        FatValue::Last => return Err(false),
-                        FatValue::Bad | FatValue::Free => return Err(false),
+                        FatValue::Next(next) => {
+                            self.current_cluster = next;
+                            self.cluster_index += 1;
+                        },
+                        FatValue::Last => return Err(Error::EndOfFile),
+                        FatValue::Bad | FatValue::Free => return Err(Error::CorruptFat),
                     }
 
                     //println!("next cluster: {}", self.current_cluster);
@@ -58,6 +115,11 @@ impl <'a, T: StorageIo> Stream<'a, T> {
                     self.sector_in_cluster += 1;
                 }
             } else {
+                // FAT12/16 root directory has a fixed size
+                if self.sector_in_cluster + 1 >= self.fs.root_dir_sectors {
+                    return Err(Error::EndOfFile);
+                }
+
                 self.sector_in_cluster += 1;
             }
             self.offset_in_sector = 0;
@@ -65,28 +127,188 @@ impl <'a, T: StorageIo> Stream<'a, T> {
         Ok(())
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, bool> {
+    // Number of whole sectors, at most `max`, from the current one on that lie in
+    // consecutive clusters. Also returns the last cluster of the chain if the
+    // sectors reach it.
+    fn contiguous_sectors(&self, max: u32) -> Result<(u32, Option<u32>), Error<T::Error>> {
+        let sectors_in_cluster = self.fs.sectors_in_cluster;
+        let mut count = core::cmp::min(max, sectors_in_cluster - self.sector_in_cluster);
+        let mut cluster = self.current_cluster;
+        let mut storage = self.fs.storage();
+        let storage_mut = storage.deref_mut();
+
+        while count < max {
+            let next = match self.fs.table.get(storage_mut, cluster)? {
+                FatValue::Next(next) => next,
+                FatValue::Last => return Ok((count, Some(cluster))),
+                _ => break,
+            };
+
+            if next != cluster + 1 {
+                break;
+            }
+
+            cluster = next;
+            count += core::cmp::min(max - count, sectors_in_cluster);
+        }
+
+        Ok((count, None))
+    }
+
+    // Finds the cluster following `prev` in the chain, it is linked once it has
+    // been written, see `Link`.
+    #[cfg(not(feature = "fs_read_only"))]
+    fn cluster_new(&self, prev: Option<u32>, link: &mut Option<Link>) -> Result<u32, Error<T::Error>> {
+        let mut storage = self.fs.storage();
+        let cluster = self.fs.table.free_find(storage.deref_mut())?;
+        *link = Some(Link { prev, first: cluster, count: 1 });
+        Ok(cluster)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn clusters_link(&self, link: Link) -> Result<(), Error<T::Error>> {
+        let mut storage = self.fs.storage();
+        let mut prev = link.prev;
+
+        for cluster in link.first..link.first + link.count {
+            self.fs.table.alloc_at(storage.deref_mut(), cluster, prev)?;
+            prev = Some(cluster);
+        }
+
+        Ok(())
+    }
+
+    // Moves past `count` sectors of a contiguous run starting at the current sector.
+    fn sectors_skip(&mut self, count: u32) {
+        let last = self.sector_in_cluster + count - 1;
+        let clusters = last / self.fs.sectors_in_cluster;
+        self.current_cluster += clusters;
+        self.cluster_index += clusters;
+        self.sector_in_cluster = last % self.fs.sectors_in_cluster;
+        self.offset_in_sector = self.fs.sector_size as usize;
+        self.global_offset += u64::from(count) * u64::from(self.fs.sector_size);
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error<T::Error>> {
         self.sync()?;
+        let sector_size = self.fs.sector_size as usize;
+
+        if !self.lenear && self.offset_in_sector == 0 && buf.len() >= sector_size {
+            // whole sectors go from the device straight into the buffer
+            let (count, _) = self.contiguous_sectors((buf.len() / sector_size) as u32)?;
+            let len = count as usize * sector_size;
+            let sector = self.fs.cluster_to_sector(self.current_cluster) + self.sector_in_cluster;
+            self.fs.storage().read_blocks(sector, &mut buf[..len])?;
+            self.sectors_skip(count);
+            return Ok(len);
+        }
+
         let len = core::cmp::min(buf.len(), (self.fs.sector_size as usize) - self.offset_in_sector);
         let sector = self.fs.cluster_to_sector(self.current_cluster) + self.sector_in_cluster;
-        self.fs.storage.borrow_mut().read(sector, self.offset_in_sector, &mut buf[..len])?;
+        self.fs.storage().read(sector, self.offset_in_sector, &mut buf[..len])?;
         self.offset_in_sector += len;
-        self.global_offset += len as u32;
+        self.global_offset += len as u64;
         Ok(len)
     }
     
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize, bool> {
-        self.sync()?;
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error<T::Error>> {
+        let mut link = None;
+
+        if self.first_cluster == 0 && !self.lenear {
+            // empty file, the first cluster is allocated on the first write
+            let cluster = self.cluster_new(None, &mut link)?;
+            self.first_cluster = cluster;
+            self.current_cluster = cluster;
+        }
+
+        match self.sync() {
+            Err(Error::EndOfFile) if !self.lenear => {
+                // the chain is over, a new cluster follows its end
+                self.current_cluster = self.cluster_new(Some(self.current_cluster), &mut link)?;
+                self.cluster_index += 1;
+                self.sector_in_cluster = 0;
+                self.offset_in_sector = 0;
+            },
+            result => result?,
+        }
+
+        let result = self.sectors_write(buf, &mut link);
+
+        // linked even if the write failed, the chain stays as long as the stream
+        if let Some(link) = link {
+            self.clusters_link(link)?;
+        }
+
+        result
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn sectors_write(&mut self, buf: &[u8], link: &mut Option<Link>) -> Result<usize, Error<T::Error>> {
+        let sector_size = self.fs.sector_size as usize;
+
+        if !self.lenear && self.offset_in_sector == 0 && buf.len() >= sector_size {
+            let max = (buf.len() / sector_size) as u32;
+            let (mut count, end) = self.contiguous_sectors(max)?;
+            // a cluster not linked yet ends the chain as well
+            let end = link.map(|link| link.first + link.count - 1).or(end);
+
+            // file data grows into the free clusters right after the chain
+            if let (Some(end), StreamKind::Data) = (end, self.kind) {
+                let mut storage = self.fs.storage();
+                let mut last = end;
+
+                while count < max && self.fs.table.is_free(storage.deref_mut(), last + 1)? {
+                    last += 1;
+                    count += core::cmp::min(max - count, self.fs.sectors_in_cluster);
+
+                    match link {
+                        Some(link) => link.count += 1,
+                        None => *link = Some(Link { prev: Some(end), first: last, count: 1 }),
+                    }
+                }
+            }
+
+            let len = count as usize * sector_size;
+            let sector = self.fs.cluster_to_sector(self.current_cluster) + self.sector_in_cluster;
+            self.fs.storage().write_blocks(sector, &buf[..len])?;
+            self.sectors_skip(count);
+            return Ok(len);
+        }
+
         let len = core::cmp::min(buf.len(), (self.fs.sector_size as usize) - self.offset_in_sector);
         let sector = self.fs.cluster_to_sector(self.current_cluster) + self.sector_in_cluster;
-        self.fs.storage.borrow_mut().write(sector, self.offset_in_sector, &buf[..len])?;
+        let mut storage = self.fs.storage();
+
+        // a cluster not linked yet is written like file data, ahead of the FAT
+        if self.kind == StreamKind::Data || link.is_some() {
+            storage.write_data(sector, self.offset_in_sector, &buf[..len])?;
+        } else {
+            storage.write(sector, self.offset_in_sector, &buf[..len])?;
+        }
+
         self.offset_in_sector += len;
-        self.global_offset += len as u32;
+        self.global_offset += len as u64;
         Ok(len)
     }
     
-    fn get_cluster(&mut self, cluster: u32, skip: u32) -> Result<FatValue, bool> {
-        let mut storage = self.fs.storage.borrow_mut();
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    // Cluster of the current position, the last one of the chain at its end.
+    pub fn cluster(&self) -> u32 {
+        self.current_cluster
+    }
+
+    // Sector and offset inside of it of the current position.
+    pub fn location(&self) -> (u32, usize) {
+        let sector = self.fs.cluster_to_sector(self.current_cluster) + self.sector_in_cluster;
+        (sector, self.offset_in_sector)
+    }
+
+    fn get_cluster(&mut self, cluster: u32, skip: u32) -> Result<FatValue, Error<T::Error>> {
+        let mut storage = self.fs.storage();
         let storage_mut = storage.deref_mut();
 
         let mut cluster = cluster;
@@ -103,49 +325,76 @@ impl <'a, T: StorageIo> Stream<'a, T> {
         Ok(FatValue::Next(cluster))
     }
 
-    pub fn seek(&mut self, pos: StreamPos) -> Result<u32, bool> {
+    pub fn seek(&mut self, pos: StreamPos) -> Result<u64, Error<T::Error>> {
         let new_pos = match pos {
-            StreamPos::Current(c) => {
-                if c == 0 {
-                    return Ok(self.global_offset);
-                }
+            StreamPos::Current(0) => return Ok(self.global_offset),
+            StreamPos::Current(c) => self.global_offset.checked_add_signed(c).ok_or(Error::InvalidSeek)?,
+            StreamPos::Start(s) => s,
+            // the stream does not know the size of its content, see File::seek
+            StreamPos::End(_) => return Err(Error::InvalidSeek),
+        };
 
-                (self.global_offset as i32) + c
-            },
-            StreamPos::Start(s) => s as i32,
-            StreamPos::End(_) => todo!(),
+        let sector_size = u64::from(self.fs.sector_size);
+
+        // A position on a sector boundary is kept at the end of the previous sector,
+        // the next read or write moves on to the following sector or cluster.
+        let (sector, offset) = if new_pos != 0 && new_pos.is_multiple_of(sector_size) {
+            (new_pos / sector_size - 1, sector_size)
+        } else {
+            (new_pos / sector_size, new_pos % sector_size)
         };
 
-        if new_pos < 0 {
-            return Err(false);
+        if sector > u64::from(u32::MAX) {
+            return Err(Error::InvalidSeek);
         }
 
-        let new_pos = new_pos as u32;
-        let cluster_size = self.fs.sectors_in_cluster * self.fs.sector_size;
-        
-        if new_pos / cluster_size != self.global_offset / cluster_size {
-            let (origin, skip) = if new_pos < self.global_offset {
-                // start search from file origin
-                let clusters_to_skip = new_pos % cluster_size;
-                (self.first_cluster, clusters_to_skip)
-            } else {
-                // start search from current position in file
-                let clusters_to_skip = new_pos % cluster_size;
-                let origin = self.global_offset % cluster_size;
-                let clusters_to_skip = clusters_to_skip - origin;
-                (self.first_cluster, clusters_to_skip)
-            };
+        let sector = sector as u32;
 
-            match self.get_cluster(origin, skip)? {
-                FatValue::Next(n) => self.current_cluster = n,
-                _ => return Err(false)
+        if self.lenear {
+            if sector >= self.fs.root_dir_sectors {
+                return Err(Error::EndOfFile);
             }
+
+            self.sector_in_cluster = sector;
+        } else {
+            let index = sector / self.fs.sectors_in_cluster;
+
+            if index != self.cluster_index {
+                if self.first_cluster == 0 {
+                    return Err(Error::EndOfFile);
+                }
+
+                let (mut origin_index, mut origin) = (0, self.first_cluster);
+
+                if let Some((mapped_index, mapped)) = self.cluster_map.as_ref().and_then(|map| map.nearest(index)) {
+                    // the map covers the whole chain unless it grew later
+                    origin_index = mapped_index;
+                    origin = mapped;
+                }
+
+                if self.cluster_index < index && self.cluster_index > origin_index {
+                    // start search from current position in file
+                    origin_index = self.cluster_index;
+                    origin = self.current_cluster;
+                }
+
+                let skip = index - origin_index;
+
+                match self.get_cluster(origin, skip)? {
+                    FatValue::Next(n) => self.current_cluster = n,
+                    FatValue::Last => return Err(Error::EndOfFile),
+                    FatValue::Bad | FatValue::Free => return Err(Error::CorruptFat),
+                }
+
+                self.cluster_index = index;
+            }
+
+            self.sector_in_cluster = sector % self.fs.sectors_in_cluster;
         }
 
-        self.sector_in_cluster = (new_pos % cluster_size) / self.fs.sector_size;
-        self.offset_in_sector = ((new_pos % cluster_size) % self.fs.sector_size) as usize;
+        self.offset_in_sector = offset as usize;
 
-        self.global_offset = new_pos as u32;
+        self.global_offset = new_pos;
         Ok(self.global_offset)
     }
 }
@@ -1,6 +1,28 @@
-use super::StorageRead;
-use super::StorageWrite;
+use super::storage::StorageRead;
+#[cfg(not(feature = "fs_read_only"))]
+use super::storage::StorageWrite;
+use super::error::Error;
+use core::cell::Cell;
+use core::ops::Range;
 
+const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+const FS_INFO_STRUC_SIG: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIG: u32 = 0xAA55_0000;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_NEXT_FREE: usize = 492;
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// Volume state bits kept in FAT entry 1, a set bit means the state is fine.
+#[cfg(not(feature = "fat16_disable"))]
+const FAT16_CLEAN_SHUTDOWN: u32 = 0x8000;
+#[cfg(not(feature = "fat16_disable"))]
+const FAT16_NO_HARD_ERROR: u32 = 0x4000;
+#[cfg(not(feature = "fat32_disable"))]
+const FAT32_CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+#[cfg(not(feature = "fat32_disable"))]
+const FAT32_NO_HARD_ERROR: u32 = 0x0400_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FatType {
 #[cfg(not(feature = "fat32_disable"))]
     Fat32,
@@ -10,6 +32,7 @@ pub enum FatType {
     Fat12
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FatValue {
     Next(u32),
     Last,
@@ -22,6 +45,16 @@ pub struct FatTable {
     first_block: u32,
     block_count: u32,
     block_size: u32,
+    cluster_count: u32,
+    fat_count: u32,
+    // the only FAT in use when FAT32 mirroring is disabled
+    active_fat: Option<u32>,
+    fs_info_block: Option<u32>,
+    next_free: Cell<u32>,
+    free_count: Cell<u32>,
+    // free count as stored in the FSInfo sector
+    fs_info_free_count: Cell<u32>,
+    fs_info_dirty: Cell<bool>,
 }
 
 fn u32_from_bytes(bytes: &[u8]) -> u32 {
@@ -34,34 +67,324 @@ fn u16_from_bytes(bytes: &[u8]) -> u16 {
 }
 
 impl FatTable {
-    pub fn new(fat_type: FatType, first_block: u32, block_count: u32, block_size: u32) -> Self {
-        FatTable { fat_type, first_block, block_count, block_size }
+    pub fn new(fat_type: FatType, first_block: u32, block_count: u32, block_size: u32, cluster_count: u32, fat_count: u32) -> Self {
+        FatTable {
+            fat_type,
+            first_block,
+            block_count,
+            block_size,
+            cluster_count,
+            fat_count,
+            active_fat: None,
+            fs_info_block: None,
+            next_free: Cell::new(2),
+            free_count: Cell::new(FS_INFO_UNKNOWN),
+            fs_info_free_count: Cell::new(FS_INFO_UNKNOWN),
+            fs_info_dirty: Cell::new(false),
+        }
+    }
+
+    // FAT32 ExtFlags: bits 0-3 hold the active FAT, bit 7 disables mirroring
+    // so that only the active FAT is read and written.
+    pub fn ext_flags_load(&mut self, ext_flags: u16) -> bool {
+        if ext_flags & 0x80 == 0 {
+            self.active_fat = None;
+            return true;
+        }
+
+        let active = u32::from(ext_flags & 0x0f);
+        self.active_fat = Some(active);
+        active < self.fat_count
+    }
+
+    fn fat_first_block(&self, fat: u32) -> u32 {
+        self.first_block + fat * self.block_count
+    }
+
+    // Blocks of the FAT `get` reads from.
+    pub fn lookup_blocks(&self) -> Range<u32> {
+        let first = self.fat_first_block(self.active_fat.unwrap_or(0));
+        first..first + self.block_count
+    }
+
+    // Last valid cluster number of the data area.
+    fn last_cluster(&self) -> u32 {
+        self.cluster_count + 1
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..=self.last_cluster()).contains(&cluster)
+    }
+
+    // Reads the FAT32 FSInfo sector. The next free and free count hints are only
+    // taken if the signatures and values are sane, otherwise they stay unknown.
+    pub fn fs_info_load<T: StorageRead>(&mut self, io: &mut T, block: u32) -> Result<(), Error<T::Error>> {
+        let mut buf = [0u8; 512];
+        io.read(block, 0, &mut buf)?;
+
+        if u32_from_bytes(&buf[0..]) != FS_INFO_LEAD_SIG ||
+            u32_from_bytes(&buf[484..]) != FS_INFO_STRUC_SIG ||
+            u32_from_bytes(&buf[508..]) != FS_INFO_TRAIL_SIG {
+            return Ok(());
+        }
+
+        self.fs_info_block = Some(block);
+
+        let free_count = u32_from_bytes(&buf[FS_INFO_FREE_COUNT..]);
+        self.fs_info_free_count.set(free_count);
+
+        if free_count <= self.cluster_count {
+            self.free_count.set(free_count);
+        }
+
+        let next_free = u32_from_bytes(&buf[FS_INFO_NEXT_FREE..]);
+
+        if self.is_valid_cluster(next_free) {
+            self.next_free.set(next_free);
+        }
+
+        Ok(())
+    }
+
+    pub fn free_count(&self) -> Option<u32> {
+        match self.free_count.get() {
+            FS_INFO_UNKNOWN => None,
+            count => Some(count),
+        }
+    }
+
+    // Drops the free count hint, it gets counted again when needed.
+    pub fn free_count_forget(&mut self) {
+        self.free_count.set(FS_INFO_UNKNOWN);
+    }
+
+    // Keeps a counted value as free count hint without rewriting FSInfo, it is
+    // written with the next change of the FAT.
+    pub fn free_count_load(&self, count: u32) {
+        self.free_count.set(count);
+    }
+
+    // True if the FSInfo sector does not hold `count` as free count.
+    pub fn fs_info_stale(&self, count: u32) -> bool {
+        self.fs_info_block.is_some() && self.fs_info_free_count.get() != count
+    }
+
+    // Replaces the free count hint with a counted value.
+    pub fn free_count_set(&self, count: u32) {
+        self.free_count.set(count);
+
+        if self.fs_info_free_count.get() != count {
+            self.fs_info_dirty.set(true);
+        }
+    }
+
+    // Scans the whole FAT, returns the number of free and bad clusters.
+    pub fn count<T: StorageRead>(&self, io: &mut T) -> Result<(u32, u32), Error<T::Error>> {
+        let mut free = 0;
+        let mut bad = 0;
+
+        for cluster in 2..=self.last_cluster() {
+            match self.get(io, cluster)? {
+                FatValue::Free => free += 1,
+                FatValue::Bad => bad += 1,
+                _ => (),
+            }
+        }
+
+        Ok((free, bad))
+    }
+
+    // Finds a free cluster starting from the next free hint, marks it as the end
+    // of a chain and links it after `prev` if given.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn alloc<T: StorageRead + StorageWrite>(&self, io: &mut T, prev: Option<u32>) -> Result<u32, Error<T::Error>> {
+        let cluster = self.free_find(io)?;
+        self.alloc_at(io, cluster, prev)?;
+        Ok(cluster)
+    }
+
+    // Finds a free cluster starting from the next free hint without taking it.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn free_find<T: StorageRead>(&self, io: &mut T) -> Result<u32, Error<T::Error>> {
+        if self.free_count.get() == 0 {
+            return Err(Error::NoSpace);
+        }
+
+        let start = if self.is_valid_cluster(self.next_free.get()) {
+            self.next_free.get()
+        } else {
+            2
+        };
+
+        let mut cluster = start;
+
+        loop {
+            if let FatValue::Free = self.get(io, cluster)? {
+                break;
+            }
+
+            cluster = if cluster >= self.last_cluster() { 2 } else { cluster + 1 };
+
+            if cluster == start {
+                self.free_count.set(0);
+                self.fs_info_dirty.set(true);
+                return Err(Error::NoSpace);
+            }
+        }
+
+        Ok(cluster)
+    }
+
+    // True if `cluster` lies on the volume and is free.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn is_free<T: StorageRead>(&self, io: &mut T, cluster: u32) -> Result<bool, Error<T::Error>> {
+        Ok(self.is_valid_cluster(cluster) && self.get(io, cluster)? == FatValue::Free)
+    }
+
+    // Marks the free `cluster` as the end of a chain and links it after `prev`
+    // if given.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn alloc_at<T: StorageRead + StorageWrite>(&self, io: &mut T, cluster: u32, prev: Option<u32>) -> Result<(), Error<T::Error>> {
+        self.set(io, cluster, FatValue::Last)?;
+
+        if let Some(prev) = prev {
+            self.set(io, prev, FatValue::Next(cluster))?;
+        }
+
+        self.next_free.set(if cluster >= self.last_cluster() { 2 } else { cluster + 1 });
+
+        if let Some(count) = self.free_count() {
+            self.free_count.set(count.saturating_sub(1));
+        }
+
+        self.fs_info_dirty.set(true);
+        Ok(())
+    }
+
+    // Returns every cluster of the chain starting at `cluster` to the free pool.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn free_chain<T: StorageRead + StorageWrite>(&self, io: &mut T, cluster: u32) -> Result<(), Error<T::Error>> {
+        let mut cluster = cluster;
+
+        loop {
+            let next = self.get(io, cluster)?;
+
+            if let FatValue::Bad | FatValue::Free = next {
+                return Err(Error::CorruptFat);
+            }
+
+            self.set(io, cluster, FatValue::Free)?;
+
+            if let Some(count) = self.free_count() {
+                self.free_count.set(count + 1);
+            }
+
+            self.fs_info_dirty.set(true);
+
+            match next {
+                FatValue::Next(n) => cluster = n,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Writes the free count and next free hints back to the FSInfo sector.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn flush<T: StorageRead + StorageWrite>(&self, io: &mut T) -> Result<(), Error<T::Error>> {
+        if !self.fs_info_dirty.get() {
+            return Ok(());
+        }
+
+        if let Some(block) = self.fs_info_block {
+            io.write(block, FS_INFO_FREE_COUNT, &self.free_count.get().to_le_bytes())?;
+            io.write(block, FS_INFO_NEXT_FREE, &self.next_free.get().to_le_bytes())?;
+            self.fs_info_free_count.set(self.free_count.get());
+        }
+
+        self.fs_info_dirty.set(false);
+        Ok(())
+    }
+
+    // Masks of the clean shutdown and no hard error bits of FAT entry 1,
+    // FAT12 has no such bits.
+    fn volume_bits(&self) -> Option<(u32, u32)> {
+        match &self.fat_type {
+            #[cfg(not(feature = "fat32_disable"))]
+            FatType::Fat32 => Some((FAT32_CLEAN_SHUTDOWN, FAT32_NO_HARD_ERROR)),
+            #[cfg(not(feature = "fat16_disable"))]
+            FatType::Fat16 => Some((FAT16_CLEAN_SHUTDOWN, FAT16_NO_HARD_ERROR)),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    // Raw value of FAT entry 1 of the FAT copy `fat`, 2 bytes on FAT16 and 4 bytes on FAT32.
+    fn entry_1_read<T: StorageRead>(&self, io: &mut T, fat: u32, len: usize) -> Result<u32, Error<T::Error>> {
+        let mut buf = [0u8; 4];
+        io.read(self.fat_first_block(fat), len, &mut buf[..len])?;
+        Ok(u32_from_bytes(&buf))
+    }
+
+    // Returns false if the volume was not unmounted cleanly or a hard error was
+    // recorded. Always true on FAT12.
+    pub fn volume_clean<T: StorageRead>(&self, io: &mut T) -> Result<bool, Error<T::Error>> {
+        match self.volume_bits() {
+            Some((clean, no_error)) => {
+                let len = if clean > 0xFFFF { 4 } else { 2 };
+                let value = self.entry_1_read(io, self.active_fat.unwrap_or(0), len)?;
+                Ok(value & clean != 0 && value & no_error != 0)
+            },
+            None => Ok(true),
+        }
+    }
+
+    // Sets or clears the clean shutdown bit of FAT entry 1, the other bits are kept.
+    #[cfg(not(feature = "fs_read_only"))]
+    pub fn volume_clean_set<T: StorageRead + StorageWrite>(&self, io: &mut T, clean: bool) -> Result<(), Error<T::Error>> {
+        let mask = match self.volume_bits() {
+            Some((mask, _)) => mask,
+            None => return Ok(()),
+        };
+
+        let len = if mask > 0xFFFF { 4 } else { 2 };
+        let fats = match self.active_fat {
+            Some(active) => active..(active + 1),
+            None => 0..self.fat_count,
+        };
+
+        for fat in fats {
+            let value = self.entry_1_read(io, fat, len)?;
+            let value = if clean { value | mask } else { value & !mask };
+            io.write(self.fat_first_block(fat), len, &value.to_le_bytes()[..len])?;
+        }
+
+        Ok(())
     }
 
     #[cfg(not(feature = "fat32_disable"))]
-    fn fat_32_get<T: StorageRead>(&self, io: &mut T, cluster: u32) -> Result<FatValue, bool> {
+    fn fat_32_get<T: StorageRead>(&self, io: &mut T, fat: u32, cluster: u32) -> Result<FatValue, Error<T::Error>> {
         let block = (cluster * 4) / self.block_size;
         let offset = (cluster * 4) % self.block_size;
 
         if block >= self.block_count {
-            return Err(false);
+            return Err(Error::CorruptFat);
         }
 
         let mut buf = [0u8; 4];
-        io.read(self.first_block + block, offset as usize, &mut buf)?;
+        io.read(self.fat_first_block(fat) + block, offset as usize, &mut buf)?;
         let val = u32_from_bytes(&buf) & 0x0FFF_FFFF;
 
         match val {
             0 => Ok(FatValue::Free),
             0x0FFF_FFF7 => Ok(FatValue::Bad),
-            0x0FFF_FFF8..=core::u32::MAX => Ok(FatValue::Last),
+            0x0FFF_FFF8..=u32::MAX => Ok(FatValue::Last),
             value => Ok(FatValue::Next(value)),
         }
     }
 
     #[cfg(not(feature = "fat32_disable"))]
     #[cfg(not(feature = "fs_read_only"))]
-    fn fat_32_set<T: StorageWrite>(&self, io: &mut T, cluster: u32, value: FatValue) -> Result<(), bool> {
+    fn fat_32_set<T: StorageWrite>(&self, io: &mut T, fat: u32, cluster: u32, value: FatValue) -> Result<(), Error<T::Error>> {
         let raw_value = match value {
             FatValue::Next(n) => n & 0x0FFF_FFFF,
             FatValue::Last => 0x0FFF_FFF8,
@@ -71,20 +394,20 @@ impl FatTable {
 
         let block = (cluster * 4) / self.block_size;
         let offset = (cluster * 4) % self.block_size;
-        io.write(self.first_block + block, offset as usize, &raw_value.to_le_bytes())
+        io.write(self.fat_first_block(fat) + block, offset as usize, &raw_value.to_le_bytes())
     }
 
     #[cfg(not(feature = "fat16_disable"))]
-    fn fat_16_get<T: StorageRead>(&self, io: &mut T, cluster: u32) -> Result<FatValue, bool> {
+    fn fat_16_get<T: StorageRead>(&self, io: &mut T, fat: u32, cluster: u32) -> Result<FatValue, Error<T::Error>> {
         let block = (cluster * 2) / self.block_size;
         let offset = (cluster * 2) % self.block_size;
 
         if block >= self.block_count {
-            return Err(false);
+            return Err(Error::CorruptFat);
         }
 
         let mut buf = [0u8; 2];
-        io.read(self.first_block + block, offset as usize, &mut buf)?;
+        io.read(self.fat_first_block(fat) + block, offset as usize, &mut buf)?;
         let val = u16_from_bytes(&buf);
 
         match val {
@@ -97,7 +420,7 @@ impl FatTable {
 
     #[cfg(not(feature = "fat16_disable"))]
     #[cfg(not(feature = "fs_read_only"))]
-    fn fat_16_set<T: StorageWrite>(&self, io: &mut T, cluster: u32, value: FatValue) -> Result<(), bool> {
+    fn fat_16_set<T: StorageWrite>(&self, io: &mut T, fat: u32, cluster: u32, value: FatValue) -> Result<(), Error<T::Error>> {
         let raw_value = match value {
             FatValue::Next(n) => n & 0xFFFF,
             FatValue::Last => 0xFFF8,
@@ -108,21 +431,32 @@ impl FatTable {
         let block = (cluster * 2) / self.block_size;
         let offset = (cluster * 2) % self.block_size;
         let raw_value = raw_value as u16;
-        io.write(self.first_block + block, offset as usize, &raw_value.to_le_bytes())
+        io.write(self.fat_first_block(fat) + block, offset as usize, &raw_value.to_le_bytes())
     }
 
+    // A FAT12 entry may straddle two sectors, so its bytes are accessed one by one.
     #[cfg(not(feature = "fat12_disable"))]
-    fn fat_12_get<T: StorageRead>(&self, io: &mut T, cluster: u32) -> Result<FatValue, bool> {
-        let block = (cluster + (cluster / 2)) / self.block_size;
-        let offset = (cluster + (cluster / 2)) % self.block_size;
+    fn fat_12_read_pair<T: StorageRead>(&self, io: &mut T, fat: u32, cluster: u32) -> Result<[u8; 2], Error<T::Error>> {
+        let position = cluster + (cluster / 2);
+        let mut buf = [0u8; 2];
 
-        if block >= self.block_count {
-            return Err(false);
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let block = (position + i as u32) / self.block_size;
+            let offset = (position + i as u32) % self.block_size;
+
+            if block >= self.block_count {
+                return Err(Error::CorruptFat);
+            }
+
+            io.read(self.fat_first_block(fat) + block, offset as usize, core::slice::from_mut(byte))?;
         }
 
-        let mut buf = [0u8; 2];
-        io.read(self.first_block + block, offset as usize, &mut buf)?;
-        let val = u16_from_bytes(&buf);
+        Ok(buf)
+    }
+
+    #[cfg(not(feature = "fat12_disable"))]
+    fn fat_12_get<T: StorageRead>(&self, io: &mut T, fat: u32, cluster: u32) -> Result<FatValue, Error<T::Error>> {
+        let val = u16_from_bytes(&self.fat_12_read_pair(io, fat, cluster)?);
 
         let raw_value = if cluster & 1 == 0 {
             (val & 0x0FFF) as u32
@@ -140,7 +474,7 @@ impl FatTable {
 
     #[cfg(not(feature = "fat12_disable"))]
     #[cfg(not(feature = "fs_read_only"))]
-    fn fat_12_set<T: StorageRead + StorageWrite>(&self, io: &mut T, cluster: u32, value: FatValue) -> Result<(), bool> {
+    fn fat_12_set<T: StorageRead + StorageWrite>(&self, io: &mut T, fat: u32, cluster: u32, value: FatValue) -> Result<(), Error<T::Error>> {
         let raw_value = match value {
             FatValue::Next(n) => n & 0xFFF,
             FatValue::Last => 0xFF8,
@@ -148,42 +482,244 @@ impl FatTable {
             FatValue::Bad => 0xFF7,
         };
 
-        let block = (cluster + (cluster / 2)) / self.block_size;
-        let offset = (cluster + (cluster / 2)) % self.block_size;
-        let mut buf = [0u8; 2];
-        io.read(self.first_block + block, offset as usize, &mut buf)?;
+        let mut buf = self.fat_12_read_pair(io, fat, cluster)?;
 
         if cluster & 1 == 0 {
             buf[0] = raw_value as u8;
-            buf[1] = (buf[1] & 0x0f) | (((raw_value >> 8) & 0x0f) as u8);
+            buf[1] = (buf[1] & 0xf0) | (((raw_value >> 8) & 0x0f) as u8);
         } else {
-            buf[0] = (buf[0] & 0xf0) | (((raw_value & 0x0f) << 4) as u8);
-            buf[1] = (raw_value >> 8) as u8;
+            buf[0] = (buf[0] & 0x0f) | (((raw_value & 0x0f) << 4) as u8);
+            buf[1] = (raw_value >> 4) as u8;
         }
-        
-        io.write(self.first_block + block, offset as usize, &buf)
+
+        let position = cluster + (cluster / 2);
+
+        for (i, byte) in buf.iter().enumerate() {
+            let block = (position + i as u32) / self.block_size;
+            let offset = (position + i as u32) % self.block_size;
+            io.write(self.fat_first_block(fat) + block, offset as usize, core::slice::from_ref(byte))?;
+        }
+
+        Ok(())
     }
 
-    pub fn get<T: StorageRead>(&self, io: &mut T, cluster: u32) -> Result<FatValue, bool> {
+    pub fn get<T: StorageRead>(&self, io: &mut T, cluster: u32) -> Result<FatValue, Error<T::Error>> {
+        // with mirroring enabled all copies are equal and the first one is used
+        let fat = self.active_fat.unwrap_or(0);
+
         match &self.fat_type {
             #[cfg(not(feature = "fat32_disable"))]
-            FatType::Fat32 => self.fat_32_get(io, cluster),
+            FatType::Fat32 => self.fat_32_get(io, fat, cluster),
             #[cfg(not(feature = "fat16_disable"))]
-            FatType::Fat16 => self.fat_16_get(io, cluster),
+            FatType::Fat16 => self.fat_16_get(io, fat, cluster),
             #[cfg(not(feature = "fat12_disable"))]
-            FatType::Fat12 => self.fat_12_get(io, cluster),
+            FatType::Fat12 => self.fat_12_get(io, fat, cluster),
         }
     }
 
     #[cfg(not(feature = "fs_read_only"))]
-    pub fn set<T: StorageRead + StorageWrite>(&self, io: &mut T, cluster: u32, value: FatValue) -> Result<(), bool> {
-        match &self.fat_type {
-            #[cfg(not(feature = "fat32_disable"))]
-            FatType::Fat32 => self.fat_32_set(io, cluster, value),
-            #[cfg(not(feature = "fat16_disable"))]
-            FatType::Fat16 => self.fat_16_set(io, cluster, value),
-            #[cfg(not(feature = "fat12_disable"))]
-            FatType::Fat12 => self.fat_12_set(io, cluster, value),
+    pub fn set<T: StorageRead + StorageWrite>(&self, io: &mut T, cluster: u32, value: FatValue) -> Result<(), Error<T::Error>> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(Error::CorruptFat);
+        }
+
+        let fats = match self.active_fat {
+            Some(active) => active..(active + 1),
+            None => 0..self.fat_count,
+        };
+
+        for fat in fats {
+            match &self.fat_type {
+                #[cfg(not(feature = "fat32_disable"))]
+                FatType::Fat32 => self.fat_32_set(io, fat, cluster, value)?,
+                #[cfg(not(feature = "fat16_disable"))]
+                FatType::Fat16 => self.fat_16_set(io, fat, cluster, value)?,
+                #[cfg(not(feature = "fat12_disable"))]
+                FatType::Fat12 => self.fat_12_set(io, fat, cluster, value)?,
+            }
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "fs_read_only"))]
+mod tests {
+    use super::*;
+    use crate::Fs;
+    use crate::test_image;
+    use crate::storage_io::StorageIo;
+    use core::ops::DerefMut;
+
+    #[test]
+    fn alloc_wrap_test() {
+        let fs = Fs::new(test_image::format(32768, 4)).unwrap();
+        let mut storage = fs.storage();
+        let table = &fs.table;
+        let last = table.last_cluster();
+
+        // the search starts at the hint and wraps around to cluster 2
+        table.next_free.set(last);
+        assert_eq!(table.alloc(storage.deref_mut(), None), Ok(last));
+        assert_eq!(table.next_free.get(), 2);
+        assert_eq!(table.alloc(storage.deref_mut(), Some(last)), Ok(2));
+        assert_eq!(table.get(storage.deref_mut(), last), Ok(FatValue::Next(2)));
+
+        // used clusters after the hint are skipped
+        table.next_free.set(last);
+        assert_eq!(table.alloc(storage.deref_mut(), None), Ok(3));
+    }
+
+    #[test]
+    fn alloc_full_test() {
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let mut storage = fs.storage();
+        let table = &fs.table;
+
+        let mut allocated = 0;
+        let mut prev = None;
+
+        loop {
+            match table.alloc(storage.deref_mut(), prev) {
+                Ok(cluster) => prev = Some(cluster),
+                Err(e) => {
+                    assert_eq!(e, Error::NoSpace);
+                    break;
+                },
+            }
+            allocated += 1;
+        }
+
+        assert_eq!(allocated, table.cluster_count);
+        assert_eq!(table.free_count(), Some(0));
+        assert_eq!(table.alloc(storage.deref_mut(), None), Err(Error::NoSpace));
+
+        // freeing the chain makes all of it available again
+        table.free_chain(storage.deref_mut(), 2).unwrap();
+        assert_eq!(table.free_count(), Some(allocated));
+        assert!(table.alloc(storage.deref_mut(), None).is_ok());
+    }
+
+    #[test]
+    fn fs_info_flush_test() {
+        let fs = Fs::new(test_image::format(140_000, 2)).unwrap();
+        let free = {
+            let mut storage = fs.storage();
+            let (free, _) = fs.table.count(storage.deref_mut()).unwrap();
+            fs.table.free_count_set(free);
+
+            let first = fs.table.alloc(storage.deref_mut(), None).unwrap();
+            let second = fs.table.alloc(storage.deref_mut(), Some(first)).unwrap();
+            fs.table.alloc(storage.deref_mut(), Some(second)).unwrap();
+            fs.table.free_chain(storage.deref_mut(), second).unwrap();
+            free
+        };
+
+        // the root directory uses cluster 2, the allocation continues at 6
+        fs.flush().unwrap();
+        let disk = fs.unmount().unwrap();
+        let mut fs_info = [0u8; 512];
+        disk.read(1, &mut fs_info).unwrap();
+        assert_eq!(u32_from_bytes(&fs_info[FS_INFO_FREE_COUNT..]), free - 1);
+        assert_eq!(u32_from_bytes(&fs_info[FS_INFO_NEXT_FREE..]), 6);
+
+        // and both hints are picked up by the next mount
+        let fs = Fs::new(disk).unwrap();
+        assert_eq!(fs.table.free_count(), Some(free - 1));
+        assert_eq!(fs.table.next_free.get(), 6);
+    }
+
+    fn fat_copies(disk: &test_image::RamDisk, first: u32, fat_size: u32, fat_count: u32) -> std::vec::Vec<std::vec::Vec<u8>> {
+        (0..fat_count).map(|fat| {
+            let mut copy = std::vec![0u8; (fat_size * 512) as usize];
+
+            for (i, block) in copy.chunks_exact_mut(512).enumerate() {
+                disk.read(first + fat * fat_size + i as u32, block).unwrap();
+            }
+
+            copy
+        }).collect()
+    }
+
+    #[test]
+    fn mirror_test() {
+        let fs = Fs::new(test_image::format(32768, 4)).unwrap();
+        let mut file = fs.root_dir().file_create(b"chain.bin").unwrap();
+        file.write(&[1u8; 20000]).unwrap();
+        file.set_len(5000, false).unwrap();
+        file.close().unwrap();
+        let (first, fat_size) = (fs.table.first_block, fs.table.block_count);
+        let disk = fs.unmount().unwrap();
+
+        let copies = fat_copies(&disk, first, fat_size, 2);
+        assert_ne!(&copies[0][4..8], [0u8; 4]);
+        assert!(copies[0] == copies[1]);
+    }
+
+    #[test]
+    fn ext_flags_test() {
+        // FAT32 with mirroring disabled and the second FAT active
+        let disk = test_image::format(140_000, 2);
+        let mut bpb = [0u8; 512];
+        disk.read(0, &mut bpb).unwrap();
+        bpb[40] = 0x81;
+        disk.write(0, &bpb).unwrap();
+
+        let fs = Fs::new(disk).unwrap();
+        let before = {
+            let storage = fs.storage();
+            fat_copies(storage.io(), fs.table.first_block, fs.table.block_count, 2)
+        };
+        assert_eq!(fs.table.lookup_blocks().start, fs.table.first_block + fs.table.block_count);
+
+        let mut file = fs.root_dir().file_create(b"active.bin").unwrap();
+        file.write(&[1u8; 3000]).unwrap();
+        file.close().unwrap();
+        let (first, fat_size) = (fs.table.first_block, fs.table.block_count);
+        let disk = fs.unmount().unwrap();
+
+        // only the active FAT holds the new chain
+        let after = fat_copies(&disk, first, fat_size, 2);
+        assert!(after[0] == before[0]);
+        assert!(after[1] != before[1]);
+        assert_eq!(u32_from_bytes(&after[1][12..]), 4);
+
+        let fs = Fs::new(disk).unwrap();
+        let mut buf = [0u8; 3000];
+        assert_eq!(fs.root_dir().file_open(b"active.bin").unwrap().read(&mut buf), Ok(3000));
+    }
+
+    #[test]
+    fn fat_12_set_test() {
+        // 1024 byte clusters, entry 341 spans the first two FAT sectors
+        let fs = Fs::new(test_image::format(2048, 2)).unwrap();
+        let mut storage = fs.storage();
+        let io = storage.deref_mut();
+        let table = &fs.table;
+        assert_eq!(table.fat_type, FatType::Fat12);
+
+        let values = [(340, 0xABC), (341, 0x123), (342, 0xFED), (343, 0x456)];
+
+        for &(cluster, value) in values.iter() {
+            table.set(io, cluster, FatValue::Next(value)).unwrap();
+        }
+
+        for &(cluster, value) in values.iter() {
+            assert_eq!(table.get(io, cluster), Ok(FatValue::Next(value)));
+        }
+
+        // changing one entry leaves the nibbles of its neighbours alone
+        table.set(io, 341, FatValue::Free).unwrap();
+        table.set(io, 342, FatValue::Last).unwrap();
+        assert_eq!(table.get(io, 340), Ok(FatValue::Next(0xABC)));
+        assert_eq!(table.get(io, 341), Ok(FatValue::Free));
+        assert_eq!(table.get(io, 342), Ok(FatValue::Last));
+        assert_eq!(table.get(io, 343), Ok(FatValue::Next(0x456)));
+
+        let mut raw = [0u8; 3];
+        io.read(table.first_block, 510, &mut raw[..2]).unwrap();
+        io.read(table.first_block + 1, 0, &mut raw[2..]).unwrap();
+        assert_eq!(raw, [0xBC, 0x0A, 0x00]);
     }
 }
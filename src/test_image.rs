@@ -0,0 +1,209 @@
+// Sparse in memory volume for the unit tests. Blocks never written read as zeros,
+// so even large volumes only cost the memory of the blocks in use.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+#[cfg(not(feature = "fs_read_only"))]
+use std::vec;
+use std::vec::Vec;
+use super::storage_io::StorageIo;
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct RamDisk {
+    blocks: RefCell<BTreeMap<u32, Vec<u8>>>,
+    block_count: u32,
+    // calls to the device, a multi-block transfer counts once
+    requests: Cell<u32>,
+    // written blocks in the order they were written
+    writes: RefCell<Vec<u32>>,
+}
+
+impl RamDisk {
+    pub fn new(block_count: u32) -> Self {
+        RamDisk {
+            blocks: RefCell::new(BTreeMap::new()),
+            block_count,
+            requests: Cell::new(0),
+            writes: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn writes_take(&self) -> Vec<u32> {
+        self.writes.replace(Vec::new())
+    }
+
+    pub fn requests_take(&self) -> u32 {
+        self.requests.replace(0)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn write_bytes(&self, offset: usize, data: &[u8]) {
+        let mut blocks = self.blocks.borrow_mut();
+
+        for (i, &b) in data.iter().enumerate() {
+            let block = ((offset + i) / BLOCK_SIZE) as u32;
+            blocks.entry(block).or_insert_with(|| vec![0u8; BLOCK_SIZE])[(offset + i) % BLOCK_SIZE] = b;
+        }
+    }
+}
+
+impl StorageIo for RamDisk {
+    type Error = ();
+
+    fn block_size(&self) -> u32 {
+        BLOCK_SIZE as u32
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), ()> {
+        self.read_blocks(block, data)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn write(&self, block: u32, data: &[u8]) -> Result<(), ()> {
+        self.write_blocks(block, data)
+    }
+
+    fn read_blocks(&self, block: u32, data: &mut [u8]) -> Result<(), ()> {
+        self.requests.set(self.requests.get() + 1);
+
+        for (i, chunk) in data.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            match self.blocks.borrow().get(&(block + i as u32)) {
+                Some(content) => chunk.copy_from_slice(content),
+                None => chunk.fill(0),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn write_blocks(&self, block: u32, data: &[u8]) -> Result<(), ()> {
+        self.requests.set(self.requests.get() + 1);
+        let mut blocks = self.blocks.borrow_mut();
+
+        for (i, chunk) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+            blocks.insert(block + i as u32, chunk.to_vec());
+            self.writes.borrow_mut().push(block + i as u32);
+        }
+
+        Ok(())
+    }
+}
+
+// Lets a test look at the disk after the file system using it is dropped.
+impl StorageIo for &RamDisk {
+    type Error = ();
+
+    fn block_size(&self) -> u32 {
+        (*self).block_size()
+    }
+
+    fn block_count(&self) -> u32 {
+        (*self).block_count()
+    }
+
+    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), ()> {
+        (*self).read(block, data)
+    }
+
+    #[cfg(not(feature = "fs_read_only"))]
+    fn write(&self, block: u32, data: &[u8]) -> Result<(), ()> {
+        (*self).write(block, data)
+    }
+}
+
+// Formats an empty volume of `total_sectors` 512 byte sectors. The FAT type
+// follows from the number of clusters, as on a real volume.
+#[cfg(not(feature = "fs_read_only"))]
+pub fn format(total_sectors: u32, sectors_in_cluster: u32) -> RamDisk {
+    let disk = RamDisk::new(total_sectors);
+    let fat_count = 2;
+
+    // FAT size and cluster count for a FAT type
+    let layout = |fat_bits: u32| {
+        let (reserved, root_entries) = if fat_bits == 32 { (32, 0) } else { (1, 512) };
+        let root_sectors = root_entries * 32 / BLOCK_SIZE as u32;
+        let mut fat_size = 1;
+
+        loop {
+            let clusters = (total_sectors - reserved - fat_count * fat_size - root_sectors) / sectors_in_cluster;
+            let needed = ((clusters + 2) * fat_bits).div_ceil(8 * BLOCK_SIZE as u32);
+
+            if needed <= fat_size {
+                return (reserved, root_entries, fat_size, clusters);
+            }
+
+            fat_size = needed;
+        }
+    };
+
+    // the type follows from the resulting number of clusters
+    let fat_bits = match (layout(12).3, layout(16).3) {
+        (clusters, _) if clusters < 4085 => 12,
+        (_, clusters) if clusters < 65525 => 16,
+        _ => 32,
+    };
+    let (reserved, root_entries, fat_size, _) = layout(fat_bits);
+
+    let mut bpb = [0u8; BLOCK_SIZE];
+    bpb[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    bpb[3..11].copy_from_slice(b"MSWIN4.1");
+    bpb[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    bpb[13] = sectors_in_cluster as u8;
+    bpb[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    bpb[16] = fat_count as u8;
+    bpb[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    bpb[21] = 0xF8;
+
+    if total_sectors < 0x10000 && fat_bits != 32 {
+        bpb[19..21].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+    } else {
+        bpb[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    }
+
+    let ebpb = if fat_bits == 32 {
+        bpb[36..40].copy_from_slice(&fat_size.to_le_bytes());
+        // root directory in cluster 2, FSInfo in sector 1
+        bpb[44..48].copy_from_slice(&2u32.to_le_bytes());
+        bpb[48..50].copy_from_slice(&1u16.to_le_bytes());
+        64
+    } else {
+        bpb[22..24].copy_from_slice(&(fat_size as u16).to_le_bytes());
+        36
+    };
+
+    bpb[ebpb + 2] = 0x29;
+    bpb[ebpb + 3..ebpb + 7].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    bpb[ebpb + 7..ebpb + 18].copy_from_slice(b"NO NAME    ");
+    bpb[510] = 0x55;
+    bpb[511] = 0xAA;
+    disk.write_bytes(0, &bpb);
+
+    if fat_bits == 32 {
+        let mut fs_info = [0u8; BLOCK_SIZE];
+        fs_info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fs_info[488..492].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        fs_info[492..496].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        fs_info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+        disk.write_bytes(BLOCK_SIZE, &fs_info);
+    }
+
+    // media byte and end of chain in entries 0 and 1, FAT32 also ends the root chain
+    let head: &[u8] = match fat_bits {
+        12 => &[0xF8, 0xFF, 0xFF],
+        16 => &[0xF8, 0xFF, 0xFF, 0xFF],
+        _ => &[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F],
+    };
+
+    for fat in 0..fat_count {
+        disk.write_bytes(((reserved + fat * fat_size) as usize) * BLOCK_SIZE, head);
+    }
+
+    disk
+}
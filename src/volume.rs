@@ -0,0 +1,164 @@
+use super::table::FatType;
+#[cfg(not(feature = "fs_read_only"))]
+use super::short_name;
+
+pub(crate) const BOOT_SIGNATURE: u8 = 0x29;
+
+// Offset of the extended BPB fields following the FAT type specific part.
+pub(crate) fn ebpb_offset(fat_type: FatType) -> usize {
+    match fat_type {
+        #[cfg(not(feature = "fat32_disable"))]
+        FatType::Fat32 => 64,
+        #[allow(unreachable_patterns)]
+        _ => 36,
+    }
+}
+
+// Offsets inside of the extended BPB.
+pub(crate) const EBPB_FLAGS: usize = 1;
+pub(crate) const EBPB_BOOT_SIG: usize = 2;
+pub(crate) const EBPB_SERIAL: usize = 3;
+pub(crate) const EBPB_LABEL: usize = 7;
+
+// Label of the BPB when the volume has none.
+#[cfg(not(feature = "fs_read_only"))]
+pub(crate) const NO_NAME: &[u8; 11] = b"NO NAME    ";
+
+pub struct VolumeInfo {
+    pub(crate) fat_type: FatType,
+    pub(crate) sector_size: u32,
+    pub(crate) cluster_size: u32,
+    pub(crate) total_sectors: u32,
+    pub(crate) data_clusters: u32,
+    pub(crate) fat_size: u32,
+    pub(crate) fat_count: u32,
+    pub(crate) serial: Option<u32>,
+    pub(crate) oem_name: [u8; 8],
+    pub(crate) bpb_label: Option<[u8; 11]>,
+    pub(crate) root_label: Option<[u8; 11]>,
+}
+
+// Cluster usage of the data area.
+pub struct Stats {
+    // in bytes
+    pub cluster_size: u32,
+    pub total_clusters: u32,
+    pub free_clusters: u32,
+    pub bad_clusters: u32,
+    pub used_clusters: u32,
+}
+
+impl Stats {
+    pub fn total_bytes(&self) -> u64 {
+        u64::from(self.total_clusters) * u64::from(self.cluster_size)
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        u64::from(self.free_clusters) * u64::from(self.cluster_size)
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        u64::from(self.used_clusters) * u64::from(self.cluster_size)
+    }
+}
+
+fn trim(name: &[u8]) -> &[u8] {
+    let len = name.iter().rposition(|&b| b != b' ' && b != 0).map_or(0, |i| i + 1);
+    &name[..len]
+}
+
+// Converts `label` to the padded uppercase form stored on the device. Labels
+// use the short name characters and may contain spaces, but not start with one.
+#[cfg(not(feature = "fs_read_only"))]
+pub(crate) fn label_raw(label: &[u8]) -> Option<[u8; 11]> {
+    if label.is_empty() || label.len() > 11 || label[0] == b' ' {
+        return None;
+    }
+
+    let mut raw = [b' '; 11];
+
+    for (r, &b) in raw.iter_mut().zip(label) {
+        if b != b' ' && !short_name::is_valid_char(b) {
+            return None;
+        }
+
+        *r = b.to_ascii_uppercase();
+    }
+
+    Some(raw)
+}
+
+impl VolumeInfo {
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    // in bytes
+    pub fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    // in bytes
+    pub fn cluster_size(&self) -> u32 {
+        self.cluster_size
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        self.total_sectors
+    }
+
+    // Number of clusters in the data area.
+    pub fn data_clusters(&self) -> u32 {
+        self.data_clusters
+    }
+
+    // Sectors occupied by a single FAT copy.
+    pub fn fat_size(&self) -> u32 {
+        self.fat_size
+    }
+
+    pub fn fat_count(&self) -> u32 {
+        self.fat_count
+    }
+
+    // Volume serial number of the extended BPB, if the boot sector has one.
+    pub fn serial(&self) -> Option<u32> {
+        self.serial
+    }
+
+    pub fn oem_name(&self) -> &[u8] {
+        trim(&self.oem_name)
+    }
+
+    // Label stored in the extended BPB, if the boot sector has one.
+    pub fn bpb_label(&self) -> Option<&[u8]> {
+        self.bpb_label.as_ref().map(|label| trim(label))
+    }
+
+    // Label stored as a volume id entry of the root directory.
+    pub fn root_label(&self) -> Option<&[u8]> {
+        self.root_label.as_ref().map(|label| trim(label))
+    }
+
+    // The label as Windows shows it, the root directory entry wins over the BPB.
+    pub fn label(&self) -> Option<&[u8]> {
+        self.root_label().or(self.bpb_label())
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "fs_read_only"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_raw_test() {
+        assert_eq!(label_raw(b"dev-0042"), Some(*b"DEV-0042   "));
+        assert_eq!(label_raw(b"My Disk"), Some(*b"MY DISK    "));
+        assert_eq!(label_raw(b"ELEVENCHARS"), Some(*b"ELEVENCHARS"));
+        assert_eq!(label_raw(b"TWELVE CHARS"), None);
+        assert_eq!(label_raw(b" LEADING"), None);
+        assert_eq!(label_raw(b"A.B"), None);
+        assert_eq!(label_raw(b""), None);
+    }
+}